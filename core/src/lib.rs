@@ -0,0 +1,2406 @@
+//! The rendering engine behind the `text-image` macros.
+//!
+//! The proc macros parse their options and call into this crate at compile time, the same
+//! engine can be used at runtime to render dynamic text:
+//!
+//! ```no_run
+//! use text_image_core::TextImageBuilder;
+//!
+//! let image = TextImageBuilder::new()
+//!     .text("Hello, world!")
+//!     .font_path("LXGWWenKaiScreen.ttf")
+//!     .font_size(24.0)
+//!     .gray_depth(4)
+//!     .build()
+//!     .unwrap();
+//! println!("{}x{}, {} bytes", image.width, image.height, image.data.len());
+//! ```
+//!
+//! The palette mapping, dithering and packing of the image macros are available as
+//! [`dither`] and [`pack_monochrome`].
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use ab_glyph::{point, Font, FontRef, GlyphId, OutlinedGlyph, PxScale, ScaleFont, VariableFont};
+use image::{imageops, GrayImage, Luma};
+use unicode_segmentation::UnicodeSegmentation;
+
+mod packbits;
+mod palette;
+
+pub use packbits::{compress, decompress};
+
+pub use palette::{
+    dither, pack_monochrome, ColorMetric, Dither, PaletteMap, ACEP_PALETTE, BWR_PALETTE,
+    BWYR_PALETTE,
+};
+
+/// Vertical alignment of a line inside its cell
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum VAlign {
+    #[default]
+    Top,
+    Middle,
+    Bottom,
+}
+
+impl FromStr for VAlign {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top" => Ok(VAlign::Top),
+            "middle" => Ok(VAlign::Middle),
+            "bottom" => Ok(VAlign::Bottom),
+            _ => Err("expected `top`, `middle` or `bottom`".to_string()),
+        }
+    }
+}
+
+/// Horizontal alignment of each line
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl FromStr for HAlign {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "left" => Ok(HAlign::Left),
+            "center" => Ok(HAlign::Center),
+            "right" => Ok(HAlign::Right),
+            _ => Err("expected `left`, `center` or `right`".to_string()),
+        }
+    }
+}
+
+/// How the image width is aligned to a byte boundary
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AlignMode {
+    /// round up to the next multiple of 8, never clips
+    #[default]
+    Up,
+    /// round to the nearest multiple of 8, may clip the last few columns
+    Nearest,
+}
+
+impl FromStr for AlignMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(AlignMode::Up),
+            "nearest" => Ok(AlignMode::Nearest),
+            _ => Err("expected `up` or `nearest`".to_string()),
+        }
+    }
+}
+
+impl AlignMode {
+    pub fn align(&self, w: u32) -> u32 {
+        match self {
+            AlignMode::Up => w.div_ceil(8) * 8,
+            AlignMode::Nearest => ((w + 4) / 8 * 8).max(8),
+        }
+    }
+}
+
+/// Pixel scan order of packed 1-bit output
+///
+/// Diagrams show which pixels of an 8x8 block land in the first bytes, `7..0` being the bit
+/// of the byte taken by the pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Scan {
+    /// row by row, each byte 8 horizontal pixels, leftmost in the MSB,
+    /// the `embedded-graphics` `ImageRaw` layout
+    ///
+    /// ```text
+    /// byte 0: x0..x7 of row 0 -> 7 6 5 4 3 2 1 0
+    /// byte 1: x0..x7 of row 1 -> 7 6 5 4 3 2 1 0
+    /// ```
+    #[default]
+    RowMsb,
+    /// row by row, each byte 8 horizontal pixels, leftmost in the LSB
+    ///
+    /// ```text
+    /// byte 0: x0..x7 of row 0 -> 0 1 2 3 4 5 6 7
+    /// byte 1: x0..x7 of row 1 -> 0 1 2 3 4 5 6 7
+    /// ```
+    RowLsb,
+    /// column by column, each byte 8 vertical pixels, topmost in the MSB
+    ///
+    /// ```text
+    ///        byte 0  byte 1
+    /// row 0    7       7
+    /// row 1    6       6
+    /// ...     ...     ...
+    /// row 7    0       0
+    ///         x0      x1
+    /// ```
+    ColMsb,
+    /// column by column, each byte 8 vertical pixels, topmost in the LSB
+    ///
+    /// ```text
+    ///        byte 0  byte 1
+    /// row 0    0       0
+    /// row 1    1       1
+    /// ...     ...     ...
+    /// row 7    7       7
+    ///         x0      x1
+    /// ```
+    ColLsb,
+    /// pages of 8 rows, each page left to right, each byte 8 vertical pixels, topmost
+    /// in the LSB, the SSD1306 / ST7565 layout
+    ///
+    /// ```text
+    ///        byte 0  byte 1  ...  byte w-1 | byte w ...
+    /// row 0    0       0            0      |  row 8
+    /// ...     ...     ...          ...     |
+    /// row 7    7       7            7      |
+    ///         x0      x1           xw-1    |
+    /// ```
+    PageVertical,
+}
+
+impl FromStr for Scan {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "row_msb" => Ok(Scan::RowMsb),
+            "row_lsb" => Ok(Scan::RowLsb),
+            "col_msb" => Ok(Scan::ColMsb),
+            "col_lsb" => Ok(Scan::ColLsb),
+            "page_vertical" => Ok(Scan::PageVertical),
+            _ => Err(
+                "expected `row_msb`, `row_lsb`, `col_msb`, `col_lsb` or `page_vertical`"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+impl Scan {
+    /// Pack a `w` x `h` 1-bit image, `is_set(x, y)` telling whether a pixel is on.
+    ///
+    /// Rows (or columns) are padded with zero bits to whole bytes.
+    pub fn pack(&self, w: u32, h: u32, is_set: impl Fn(u32, u32) -> bool) -> Vec<u8> {
+        let mut ret = vec![];
+        match self {
+            Scan::RowMsb | Scan::RowLsb => {
+                for y in 0..h {
+                    for x0 in (0..w).step_by(8) {
+                        ret.push(self.pack_byte(|i| x0 + i < w && is_set(x0 + i, y)));
+                    }
+                }
+            }
+            Scan::ColMsb | Scan::ColLsb => {
+                for x in 0..w {
+                    for y0 in (0..h).step_by(8) {
+                        ret.push(self.pack_byte(|i| y0 + i < h && is_set(x, y0 + i)));
+                    }
+                }
+            }
+            Scan::PageVertical => {
+                for y0 in (0..h).step_by(8) {
+                    for x in 0..w {
+                        ret.push(self.pack_byte(|i| y0 + i < h && is_set(x, y0 + i)));
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// whether the data can be used as an `ImageRaw<BinaryColor>`
+    pub fn is_image_raw(&self) -> bool {
+        *self == Scan::RowMsb
+    }
+
+    fn pack_byte(&self, is_set: impl Fn(u32) -> bool) -> u8 {
+        let msb_first = matches!(self, Scan::RowMsb | Scan::ColMsb);
+        (0..8)
+            .filter(|&i| is_set(i))
+            .fold(0, |n, i| n | if msb_first { 0x80 >> i } else { 1 << i })
+    }
+}
+
+/// Options of a text image, see the `text_image!` macro for their meaning
+#[derive(Debug, Clone)]
+pub struct TextImageOptions {
+    pub text: String,
+    pub font: PathBuf,
+    /// font file contents, used instead of reading `font`
+    pub font_data: Option<Vec<u8>>,
+    /// fonts tried in order for the chars `font` has no glyph for
+    pub fallback_fonts: Vec<PathBuf>,
+    pub font_size: f32,
+    pub inverse: bool,
+    /// background luma, default 0x00, or 0xFF with `inverse`
+    pub background: Option<u8>,
+    /// text luma, default 0xFF, or 0x00 with `inverse`
+    pub foreground: Option<u8>,
+    pub line_spacing: i32,
+    /// 1, 2, 4, or 8
+    pub gray_depth: u8,
+    /// gamma < 1.0, darker, gamma > 1.0, lighter
+    pub gamma: f32,
+    /// gamma applied to glyph coverage before blending, < 1.0 thickens thin strokes
+    pub coverage_gamma: f32,
+    /// blank space around the text, top, right, bottom, left like CSS
+    pub padding: [u32; 4],
+    /// wrap lines to fit this width in pixels, at whitespace or between CJK characters
+    pub max_width: Option<u32>,
+    /// fixed height of each line cell, overrides line height and spacing
+    pub cell_height: Option<i32>,
+    /// height of blank lines instead of the line height, ignored with `cell_height`
+    pub blank_line_height: Option<i32>,
+    /// fixed height of the image, the text block is placed by `valign` and clipped at the bottom
+    pub canvas_height: Option<u32>,
+    /// vertical alignment of a line inside its cell, and of the text inside the canvas
+    pub valign: VAlign,
+    /// reorder mixed LTR/RTL runs into visual order, needs the `bidi` feature
+    pub bidi: bool,
+    /// right-to-left text, lines start at the right edge and runs are reordered with a
+    /// right-to-left base direction
+    pub rtl: bool,
+    /// compose the text to Unicode NFC before layout, needs the `normalize` feature
+    pub normalize: bool,
+    /// clockwise rotation of the rendered image, 0, 90, 180 or 270 degrees
+    pub rotate: u32,
+    /// character sequences replaced by a ligature codepoint before layout
+    pub ligatures: Vec<(String, char)>,
+    /// characters the font must have glyphs for, regardless of `text`
+    pub require_chars: String,
+    /// variation axis values of a variable font, e.g. `("wght", 700.0)`
+    pub axes: Vec<(String, f32)>,
+    /// vertical offset of each glyph of a line, cycled, negative moves up
+    pub offsets: Vec<i32>,
+    /// position of the top-left of the first line
+    pub origin_x: i32,
+    pub origin_y: i32,
+    /// clamp the ascent used for line height, in pixels
+    pub max_ascent: Option<f32>,
+    /// clamp the ascent used for line height to the cap height
+    pub cap_metrics: bool,
+    /// horizontal alignment of each line
+    pub align: HAlign,
+    /// byte alignment of the width
+    pub align_mode: AlignMode,
+    /// lay out "key\tvalue" with value flush right, to `max_width` if set, filling the gap
+    /// with this string
+    pub leader: Option<String>,
+    /// pixel scan order of 1-bit output
+    pub scan: Scan,
+    /// ordered dithering of anti-aliased edges for 1-bit output, instead of thresholding
+    pub dither_edges: bool,
+    /// synthetic bold, each line is drawn again 1px to the right and 1px down
+    pub bold: bool,
+    /// synthetic italic, glyphs are sheared right by `ITALIC_SLANT`
+    pub italic: bool,
+    /// extra pixels between glyphs, negative tightens
+    pub letter_spacing: i32,
+    /// line below the baseline of each line of text
+    pub underline: bool,
+    /// line through the middle of the x-height of each line of text
+    pub strikethrough: bool,
+    /// tabs are expanded to the next multiple of this many columns, unless with `leader`
+    pub tab_width: u32,
+    /// luma from which a pixel is set in 1-bit output
+    pub threshold: u8,
+    /// round to the nearest level of Gray2 and Gray4 instead of truncating
+    pub rounding: bool,
+    /// Floyd-Steinberg error diffusion to the levels of Gray2 and Gray4
+    pub dither: bool,
+    /// at most this many lines after wrapping, the last one cut short with `ellipsis`
+    pub max_lines: Option<u32>,
+    /// appended to the last line when lines are cut by `max_lines`
+    pub ellipsis: String,
+    /// pick the largest font size whose image is at most this wide, aligned like the width
+    pub fit_width: Option<u32>,
+    /// pick the largest font size whose image is at most this high
+    pub fit_height: Option<u32>,
+}
+
+impl Default for TextImageOptions {
+    fn default() -> Self {
+        TextImageOptions {
+            text: "".to_string(),
+            font: PathBuf::new(),
+            font_data: None,
+            fallback_fonts: vec![],
+            font_size: 16.0,
+            inverse: false,
+            background: None,
+            foreground: None,
+            line_spacing: 0,
+            gray_depth: 1,
+            gamma: 1.0,
+            coverage_gamma: 1.0,
+            padding: [0; 4],
+            max_width: None,
+            cell_height: None,
+            blank_line_height: None,
+            canvas_height: None,
+            valign: VAlign::Top,
+            bidi: false,
+            rtl: false,
+            normalize: false,
+            rotate: 0,
+            ligatures: vec![],
+            require_chars: String::new(),
+            axes: vec![],
+            offsets: vec![],
+            origin_x: 0,
+            origin_y: 0,
+            max_ascent: None,
+            cap_metrics: false,
+            align: HAlign::Left,
+            align_mode: AlignMode::Up,
+            leader: None,
+            scan: Scan::RowMsb,
+            dither_edges: false,
+            bold: false,
+            italic: false,
+            letter_spacing: 0,
+            underline: false,
+            strikethrough: false,
+            tab_width: 4,
+            threshold: 128,
+            rounding: false,
+            dither: false,
+            max_lines: None,
+            ellipsis: "…".to_string(),
+            fit_width: None,
+            fit_height: None,
+        }
+    }
+}
+
+/// A rendered text image, packed to its gray depth
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+    /// width of the content before `width` was aligned to a byte, e.g. for centering the
+    /// image on a display; at most `width`
+    pub content_width: u32,
+    /// chars of the text none of the fonts has a glyph for, drawn as the font's `.notdef`
+    pub missing_glyphs: Vec<char>,
+    /// the text overflows `fit_width`/`fit_height` even at `MIN_FIT_SIZE` and was clipped
+    pub clipped: bool,
+}
+
+#[derive(Debug)]
+pub enum TextImageError {
+    /// no text to render
+    MissingText,
+    /// no font given
+    MissingFont,
+    /// the font file can not be read
+    ReadFont(PathBuf, std::io::Error),
+    /// the font file can not be parsed
+    InvalidFont(PathBuf),
+    /// gray depth other than 1, 2, 4 or 8
+    InvalidGrayDepth(u8),
+    /// rotation other than 0, 90, 180 or 270 degrees
+    InvalidRotation(u32),
+    /// a negative `blank_line_height` or a `cell_height` that isn't positive, the option
+    /// name and its value
+    InvalidHeight(&'static str, i32),
+    /// axes are given but the font has no variation axes
+    NotVariableFont(PathBuf),
+    /// the axis tag is not one of the available axes of the font
+    UnknownAxis(String, Vec<String>),
+    /// the font has no glyphs for these required characters
+    MissingGlyphs(PathBuf, Vec<char>),
+}
+
+impl fmt::Display for TextImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextImageError::MissingText => write!(f, "required option `text` is missing"),
+            TextImageError::MissingFont => {
+                write!(f, "required option `font` or `font_data` is missing")
+            }
+            TextImageError::ReadFont(path, err) => {
+                write!(f, "Can not read font file {}: {}", path.display(), err)
+            }
+            TextImageError::InvalidFont(path) => {
+                write!(f, "Can not load font {}", path.display())
+            }
+            TextImageError::InvalidGrayDepth(depth) => {
+                write!(f, "invalid gray depth {}, expected 1, 2, 4 or 8", depth)
+            }
+            TextImageError::InvalidRotation(rotate) => {
+                write!(f, "invalid rotation {}, expected 0, 90, 180 or 270", rotate)
+            }
+            TextImageError::InvalidHeight(option, height) => {
+                let expected = match *option {
+                    "cell_height" => "must be positive",
+                    _ => "must not be negative",
+                };
+                write!(f, "{} {}, got {}", option, expected, height)
+            }
+            TextImageError::NotVariableFont(path) => {
+                write!(f, "font {} is not a variable font", path.display())
+            }
+            TextImageError::MissingGlyphs(path, chars) => {
+                let chars: Vec<String> = chars.iter().map(|c| format!("{:?}", c)).collect();
+                write!(
+                    f,
+                    "font {} has no glyphs for required characters: {}",
+                    path.display(),
+                    chars.join(", ")
+                )
+            }
+            TextImageError::UnknownAxis(tag, axes) => {
+                write!(
+                    f,
+                    "unknown variation axis `{}`, the font has: {}",
+                    tag,
+                    axes.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TextImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TextImageError::ReadFont(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Builder of a [`TextImage`], mirroring the options of the `text_image!` macro
+#[derive(Debug, Clone, Default)]
+pub struct TextImageBuilder {
+    opts: TextImageOptions,
+}
+
+impl From<TextImageOptions> for TextImageBuilder {
+    fn from(opts: TextImageOptions) -> Self {
+        TextImageBuilder { opts }
+    }
+}
+
+impl TextImageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.opts.text = text.into();
+        self
+    }
+
+    pub fn font_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.opts.font = path.into();
+        self
+    }
+
+    /// Use the font file contents instead of a path
+    pub fn font_data(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.opts.font_data = Some(data.into());
+        self
+    }
+
+    pub fn fallback_font(mut self, path: impl Into<PathBuf>) -> Self {
+        self.opts.fallback_fonts.push(path.into());
+        self
+    }
+
+    pub fn font_size(mut self, font_size: f32) -> Self {
+        self.opts.font_size = font_size;
+        self
+    }
+
+    pub fn inverse(mut self, inverse: bool) -> Self {
+        self.opts.inverse = inverse;
+        self
+    }
+
+    pub fn background(mut self, background: u8) -> Self {
+        self.opts.background = Some(background);
+        self
+    }
+
+    pub fn foreground(mut self, foreground: u8) -> Self {
+        self.opts.foreground = Some(foreground);
+        self
+    }
+
+    pub fn line_spacing(mut self, line_spacing: i32) -> Self {
+        self.opts.line_spacing = line_spacing;
+        self
+    }
+
+    /// 1, 2, 4 or 8 bits per pixel
+    pub fn gray_depth(mut self, gray_depth: u8) -> Self {
+        self.opts.gray_depth = gray_depth;
+        self
+    }
+
+    pub fn gamma(mut self, gamma: f32) -> Self {
+        self.opts.gamma = gamma;
+        self
+    }
+
+    pub fn coverage_gamma(mut self, coverage_gamma: f32) -> Self {
+        self.opts.coverage_gamma = coverage_gamma;
+        self
+    }
+
+    /// Blank space around the text, `[top, right, bottom, left]`
+    pub fn padding(mut self, padding: [u32; 4]) -> Self {
+        self.opts.padding = padding;
+        self
+    }
+
+    pub fn max_width(mut self, max_width: u32) -> Self {
+        self.opts.max_width = Some(max_width);
+        self
+    }
+
+    pub fn cell_height(mut self, cell_height: i32) -> Self {
+        self.opts.cell_height = Some(cell_height);
+        self
+    }
+
+    pub fn blank_line_height(mut self, blank_line_height: i32) -> Self {
+        self.opts.blank_line_height = Some(blank_line_height);
+        self
+    }
+
+    pub fn canvas_height(mut self, canvas_height: u32) -> Self {
+        self.opts.canvas_height = Some(canvas_height);
+        self
+    }
+
+    pub fn valign(mut self, valign: VAlign) -> Self {
+        self.opts.valign = valign;
+        self
+    }
+
+    /// Reorder mixed LTR/RTL text into visual order, only effective with the `bidi` feature
+    pub fn bidi(mut self, bidi: bool) -> Self {
+        self.opts.bidi = bidi;
+        self
+    }
+
+    /// Lay out right-to-left text, without the `bidi` feature each line is simply reversed
+    pub fn rtl(mut self, rtl: bool) -> Self {
+        self.opts.rtl = rtl;
+        self
+    }
+
+    /// Compose the text to NFC before layout, only effective with the `normalize` feature
+    pub fn normalize(mut self, normalize: bool) -> Self {
+        self.opts.normalize = normalize;
+        self
+    }
+
+    pub fn rotate(mut self, rotate: u32) -> Self {
+        self.opts.rotate = rotate;
+        self
+    }
+
+    /// Replace `seq` by the ligature `c` before layout, in the order added
+    pub fn ligature(mut self, seq: impl Into<String>, c: char) -> Self {
+        self.opts.ligatures.push((seq.into(), c));
+        self
+    }
+
+    /// Fail unless the font has glyphs for all of `chars`
+    pub fn require_chars(mut self, chars: impl Into<String>) -> Self {
+        self.opts.require_chars = chars.into();
+        self
+    }
+
+    /// Select an instance of a variable font by an axis value, e.g. `axis("wght", 700.0)`
+    pub fn axis(mut self, tag: impl Into<String>, value: f32) -> Self {
+        self.opts.axes.push((tag.into(), value));
+        self
+    }
+
+    pub fn offsets(mut self, offsets: impl Into<Vec<i32>>) -> Self {
+        self.opts.offsets = offsets.into();
+        self
+    }
+
+    /// Move the text by (x, y), `origin(1, -1)` gives the layout of text-image 0.2 and earlier
+    pub fn origin(mut self, x: i32, y: i32) -> Self {
+        self.opts.origin_x = x;
+        self.opts.origin_y = y;
+        self
+    }
+
+    pub fn max_ascent(mut self, max_ascent: f32) -> Self {
+        self.opts.max_ascent = Some(max_ascent);
+        self
+    }
+
+    pub fn cap_metrics(mut self, cap_metrics: bool) -> Self {
+        self.opts.cap_metrics = cap_metrics;
+        self
+    }
+
+    /// Horizontal alignment of each line
+    pub fn align(mut self, align: HAlign) -> Self {
+        self.opts.align = align;
+        self
+    }
+
+    /// How the width is aligned to a byte boundary
+    pub fn align_mode(mut self, align_mode: AlignMode) -> Self {
+        self.opts.align_mode = align_mode;
+        self
+    }
+
+    /// Put the text after a tab flush right, with the gap filled by `leader`, e.g. `"."`
+    ///
+    /// With `max_width`, such lines span the whole width.
+    pub fn leader(mut self, leader: impl Into<String>) -> Self {
+        self.opts.leader = Some(leader.into());
+        self
+    }
+
+    pub fn scan(mut self, scan: Scan) -> Self {
+        self.opts.scan = scan;
+        self
+    }
+
+    pub fn dither_edges(mut self, dither_edges: bool) -> Self {
+        self.opts.dither_edges = dither_edges;
+        self
+    }
+
+    pub fn bold(mut self, bold: bool) -> Self {
+        self.opts.bold = bold;
+        self
+    }
+
+    pub fn italic(mut self, italic: bool) -> Self {
+        self.opts.italic = italic;
+        self
+    }
+
+    pub fn letter_spacing(mut self, letter_spacing: i32) -> Self {
+        self.opts.letter_spacing = letter_spacing;
+        self
+    }
+
+    pub fn underline(mut self, underline: bool) -> Self {
+        self.opts.underline = underline;
+        self
+    }
+
+    pub fn strikethrough(mut self, strikethrough: bool) -> Self {
+        self.opts.strikethrough = strikethrough;
+        self
+    }
+
+    pub fn tab_width(mut self, tab_width: u32) -> Self {
+        self.opts.tab_width = tab_width;
+        self
+    }
+
+    pub fn threshold(mut self, threshold: u8) -> Self {
+        self.opts.threshold = threshold;
+        self
+    }
+
+    pub fn rounding(mut self, rounding: bool) -> Self {
+        self.opts.rounding = rounding;
+        self
+    }
+
+    pub fn dither(mut self, dither: bool) -> Self {
+        self.opts.dither = dither;
+        self
+    }
+
+    pub fn max_lines(mut self, max_lines: u32) -> Self {
+        self.opts.max_lines = Some(max_lines);
+        self
+    }
+
+    pub fn ellipsis(mut self, ellipsis: impl Into<String>) -> Self {
+        self.opts.ellipsis = ellipsis.into();
+        self
+    }
+
+    pub fn fit_width(mut self, fit_width: u32) -> Self {
+        self.opts.fit_width = Some(fit_width);
+        self
+    }
+
+    pub fn fit_height(mut self, fit_height: u32) -> Self {
+        self.opts.fit_height = Some(fit_height);
+        self
+    }
+
+    pub fn build(self) -> Result<TextImage, TextImageError> {
+        if self.opts.fit_width.is_some() || self.opts.fit_height.is_some() {
+            render_fit(&self.opts)
+        } else {
+            render(&self.opts)
+        }
+    }
+
+    /// Size `(w, h)` of the image `build` gives, without drawing it
+    pub fn measure(self) -> Result<(u32, u32), TextImageError> {
+        if self.opts.fit_width.is_some() || self.opts.fit_height.is_some() {
+            let (font_size, _) = fit_font_size(&self.opts)?;
+            measure(&TextImageOptions {
+                font_size,
+                ..self.opts
+            })
+        } else {
+            measure(&self.opts)
+        }
+    }
+}
+
+/// Render a text image at runtime, e.g. for text read from a sensor.
+///
+/// Returns `(w, h, raw)`, byte for byte what `text_image!` expands to for the same options.
+/// Relative font paths are resolved against the working directory, not a crate manifest.
+pub fn text_image(opts: &TextImageOptions) -> Result<(u32, u32, Vec<u8>), TextImageError> {
+    let image = TextImageBuilder::from(opts.clone()).build()?;
+    Ok((image.width, image.height, image.data))
+}
+
+/// Size `(w, h)` of the image `text_image` gives the same options, without drawing it.
+pub fn text_image_size(opts: &TextImageOptions) -> Result<(u32, u32), TextImageError> {
+    TextImageBuilder::from(opts.clone()).measure()
+}
+
+/// Smallest font size tried by `fit_width`/`fit_height`
+pub const MIN_FIT_SIZE: f32 = 6.0;
+
+/// Iterations of the font size search, a fraction of a pixel from the best size
+const FIT_ITERATIONS: usize = 12;
+
+/// Render with the largest font size for which the image fits `fit_width` and `fit_height`.
+///
+/// If even `MIN_FIT_SIZE` overflows, the image at that size is clipped to the box.
+fn render_fit(opts: &TextImageOptions) -> Result<TextImage, TextImageError> {
+    let (font_size, fits) = fit_font_size(opts)?;
+    let mut image = render(&TextImageOptions {
+        font_size,
+        ..opts.clone()
+    })?;
+    image.clipped = !fits;
+    Ok(image)
+}
+
+/// The largest font size for which the image fits `fit_width` and `fit_height`, and whether
+/// it fits at all, `MIN_FIT_SIZE` if it doesn't.
+///
+/// Sizes are searched by bisection between `MIN_FIT_SIZE` and an upper bound no text can
+/// fit in, laying out the whole image at each step so all options are taken into account.
+fn fit_font_size(opts: &TextImageOptions) -> Result<(f32, bool), TextImageError> {
+    let max_width = opts.fit_width.map(|w| opts.align_mode.align(w));
+    let fits = |(w, h): (u32, u32)| {
+        max_width.is_none_or(|max_width| w <= max_width)
+            && opts.fit_height.is_none_or(|max_height| h <= max_height)
+    };
+    // measure without clipping to the box
+    let mut trial = opts.clone();
+    trial.fit_width = None;
+    trial.fit_height = None;
+    let mut measure_at = |font_size: f32| {
+        trial.font_size = font_size;
+        measure(&trial)
+    };
+
+    if !fits(measure_at(MIN_FIT_SIZE)?) {
+        return Ok((MIN_FIT_SIZE, false));
+    }
+    // a glyph is rarely narrower than a quarter of the font size, and never much shorter
+    let mut low = MIN_FIT_SIZE;
+    let mut high = match (opts.fit_width, opts.fit_height) {
+        (_, Some(h)) => h as f32 * 2.0,
+        (Some(w), None) => w as f32 * 4.0,
+        (None, None) => unreachable!(),
+    }
+    .max(low);
+    for _ in 0..FIT_ITERATIONS {
+        let mid = (low + high) / 2.0;
+        if fits(measure_at(mid)?) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Ok((low, true))
+}
+
+/// Contents of a font file and its modification time when read
+type FontFile = (SystemTime, Arc<[u8]>);
+
+/// Font files read so far
+static FONT_FILES: Mutex<BTreeMap<PathBuf, FontFile>> = Mutex::new(BTreeMap::new());
+
+/// Read a font file, or reuse its contents if it was read before and hasn't changed since.
+///
+/// Many images are usually rendered with the same few fonts, which can be tens of MB for
+/// CJK. Parsing a `FontRef` from the contents is cheap, tables are only read when used:
+/// it takes under a microsecond whatever the size of the file, reading a 40 MB one tens of
+/// milliseconds. So only the contents are kept, and the fonts borrowing them are parsed again.
+fn read_font(path: &Path) -> std::io::Result<Arc<[u8]>> {
+    // a long-running process, e.g. an IDE's proc macro server, may see the file change
+    let Ok(modified) = std::fs::metadata(path).and_then(|meta| meta.modified()) else {
+        return std::fs::read(path).map(Arc::from);
+    };
+    if let Some((read_at, data)) = FONT_FILES.lock().unwrap().get(path) {
+        if *read_at == modified {
+            return Ok(data.clone());
+        }
+    }
+    let data: Arc<[u8]> = std::fs::read(path)?.into();
+    FONT_FILES
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (modified, data.clone()));
+    Ok(data)
+}
+
+/// Lay out the text of `opts` and pass the layout to `f`, which borrows the fonts read.
+///
+/// Everything up to the size of the canvas is done here, so measuring doesn't draw.
+fn with_layout<R>(
+    opts: &TextImageOptions,
+    f: impl FnOnce(Layout<'_>) -> R,
+) -> Result<R, TextImageError> {
+    if opts.text.is_empty() {
+        return Err(TextImageError::MissingText);
+    }
+    if opts.font.as_os_str().is_empty() && opts.font_data.is_none() {
+        return Err(TextImageError::MissingFont);
+    }
+    if ![1, 2, 4, 8].contains(&opts.gray_depth) {
+        return Err(TextImageError::InvalidGrayDepth(opts.gray_depth));
+    }
+    if ![0, 90, 180, 270].contains(&opts.rotate) {
+        return Err(TextImageError::InvalidRotation(opts.rotate));
+    }
+    if let Some(height) = opts.blank_line_height.filter(|&height| height < 0) {
+        return Err(TextImageError::InvalidHeight("blank_line_height", height));
+    }
+    if let Some(height) = opts.cell_height.filter(|&height| height <= 0) {
+        return Err(TextImageError::InvalidHeight("cell_height", height));
+    }
+
+    // named in errors
+    let font_name = match opts.font_data {
+        Some(_) => PathBuf::from("<font_data>"),
+        None => opts.font.clone(),
+    };
+    let font_file;
+    let font_raw = match &opts.font_data {
+        Some(data) => &data[..],
+        None => {
+            font_file = read_font(&opts.font)
+                .map_err(|err| TextImageError::ReadFont(opts.font.clone(), err))?;
+            &font_file[..]
+        }
+    };
+    let mut font = FontRef::try_from_slice(font_raw)
+        .map_err(|_| TextImageError::InvalidFont(font_name.clone()))?;
+
+    // select the instance before any measurement
+    if !opts.axes.is_empty() {
+        let variations = font.variations();
+        if variations.is_empty() {
+            return Err(TextImageError::NotVariableFont(font_name));
+        }
+        for (tag, value) in &opts.axes {
+            let known = <[u8; 4]>::try_from(tag.as_bytes())
+                .is_ok_and(|tag| font.set_variation(&tag, *value));
+            if !known {
+                let axes = variations
+                    .iter()
+                    .map(|axis| String::from_utf8_lossy(&axis.tag).into_owned())
+                    .collect();
+                return Err(TextImageError::UnknownAxis(tag.clone(), axes));
+            }
+        }
+    }
+
+    let fallback_raw = opts
+        .fallback_fonts
+        .iter()
+        .map(|path| read_font(path).map_err(|err| TextImageError::ReadFont(path.clone(), err)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut fonts = vec![font];
+    for (path, raw) in opts.fallback_fonts.iter().zip(&fallback_raw) {
+        fonts.push(
+            FontRef::try_from_slice(raw).map_err(|_| TextImageError::InvalidFont(path.clone()))?,
+        );
+    }
+
+    let mut missing: Vec<char> = opts
+        .require_chars
+        .chars()
+        .filter(|c| !c.is_whitespace() && fonts.iter().all(|font| font.glyph_id(*c).0 == 0))
+        .collect();
+    if !missing.is_empty() {
+        missing.sort_unstable();
+        missing.dedup();
+        return Err(TextImageError::MissingGlyphs(font_name, missing));
+    }
+
+    let mut text = opts.text.clone();
+    // decomposed marks are placed badly, precomposed glyphs are drawn as designed; the whole
+    // text at once, a mark may start a line
+    #[cfg(feature = "normalize")]
+    if opts.normalize && !unicode_normalization::is_nfc(&text) {
+        use unicode_normalization::UnicodeNormalization;
+        text = text.nfc().collect();
+    }
+    for (seq, c) in &opts.ligatures {
+        text = text.replace(seq, &c.to_string());
+    }
+
+    // only the fonts some char is drawn with count for the line metrics
+    let mut used = vec![false; fonts.len()];
+    used[0] = true;
+    let mut missing_glyphs = vec![];
+    let ellipsis = opts.max_lines.map(|_| opts.ellipsis.as_str());
+    for cluster in text
+        .graphemes(true)
+        .chain(opts.leader.iter().flat_map(|leader| leader.graphemes(true)))
+        .chain(
+            ellipsis
+                .iter()
+                .flat_map(|ellipsis| ellipsis.graphemes(true)),
+        )
+    {
+        let font = font_for_cluster(&fonts, cluster);
+        used[font] = true;
+        for c in cluster.chars() {
+            if fonts[font].glyph_id(c).0 == 0 && !c.is_whitespace() && !c.is_control() {
+                missing_glyphs.push(c);
+            }
+        }
+    }
+    missing_glyphs.sort_unstable();
+    missing_glyphs.dedup();
+    let fonts: Vec<FontRef> = fonts
+        .into_iter()
+        .zip(used)
+        .filter_map(|(font, used)| used.then_some(font))
+        .collect();
+
+    let [pad_top, pad_right, pad_bottom, pad_left] = opts.padding.map(|pad| pad as i32);
+    // the extra strokes of synthetic bold take one more pixel to the right and below
+    let bold = opts.bold as i32;
+
+    let scale = PxScale {
+        x: opts.font_size,
+        y: opts.font_size,
+    };
+
+    let (font_ascent, descent, line_gap) = line_metrics(&fonts, scale);
+    let mut ascent = font_ascent;
+    if opts.cap_metrics {
+        // cap height, measured from the outline of `H`
+        let sfont = fonts[0].as_scaled(scale);
+        let glyph = sfont.scaled_glyph('H');
+        if let Some(outlined) = sfont.outline_glyph(glyph) {
+            ascent = ascent.min(-outlined.px_bounds().min.y);
+        }
+    }
+    if let Some(max_ascent) = opts.max_ascent {
+        ascent = ascent.min(max_ascent);
+    }
+    // glyphs are drawn from the font ascent, shift them up to the clamped ascent
+    let mut ascent_shift = (font_ascent - ascent).round() as i32;
+    let mut line_height = (ascent - descent + line_gap).abs().ceil() as i32;
+    // some glyphs reach outside of the font's ascent and descent, grow the line to fit them,
+    // at the top only if the ascent isn't clamped on purpose
+    if let Some((glyph_top, glyph_bottom)) = glyph_extent(&fonts, scale, &text) {
+        let clamped = opts.cap_metrics || opts.max_ascent.is_some();
+        let overflow_top = if clamped {
+            0
+        } else {
+            (-glyph_top).max(0.0).ceil() as i32
+        };
+        let overflow_bottom = (glyph_bottom - (ascent_shift + line_height) as f32)
+            .max(0.0)
+            .ceil() as i32;
+        ascent_shift -= overflow_top;
+        line_height += overflow_top + overflow_bottom;
+    }
+    let slant = if opts.italic { ITALIC_SLANT } else { 0.0 };
+    // bold strokes and the italic shear stick out to the right of the advance width
+    let extra_width = bold + (slant * line_height as f32).ceil() as i32;
+
+    let spacing = opts.letter_spacing;
+
+    // room for the text of a line inside `max_width`
+    let wrap_width = opts.max_width.map(|max_width| {
+        max_width
+            .saturating_sub(opts.origin_x.max(0) as u32)
+            .saturating_sub((pad_left + pad_right + extra_width) as u32)
+    });
+    let mut text_lines: Vec<String> = text
+        .lines()
+        // `lines` keeps the `\r` of a last line ending in a lone `\r`
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .map(|line| match opts.leader {
+            // a leader takes the tab as the key and value separator
+            Some(_) => line.to_string(),
+            None => expand_tabs(line, opts.tab_width),
+        })
+        .flat_map(|line| match wrap_width {
+            Some(wrap_width) => wrap_line(&fonts, scale, spacing, &line, wrap_width),
+            None => vec![line],
+        })
+        .collect();
+    if let Some(max_lines) = opts.max_lines {
+        let max_lines = (max_lines as usize).max(1);
+        if text_lines.len() > max_lines {
+            text_lines.truncate(max_lines);
+            // the last line and the ellipsis fit in `max_width`, or the widest line kept
+            let room = wrap_width.unwrap_or_else(|| {
+                text_lines
+                    .iter()
+                    .map(|line| text_width(&fonts, scale, spacing, line))
+                    .max()
+                    .unwrap_or(0)
+            });
+            let last = text_lines.last_mut().unwrap();
+            loop {
+                *last = last.trim_end().to_string();
+                let candidate = format!("{}{}", last, opts.ellipsis);
+                if last.is_empty() || text_width(&fonts, scale, spacing, &candidate) <= room {
+                    *last = candidate;
+                    break;
+                }
+                // never split a char from its combining marks
+                let end = last
+                    .grapheme_indices(true)
+                    .next_back()
+                    .map_or(0, |(i, _)| i);
+                last.truncate(end);
+            }
+        }
+    }
+    #[cfg(feature = "bidi")]
+    let text_lines: Vec<String> = if opts.bidi || opts.rtl {
+        text_lines
+            .iter()
+            .map(|line| bidi_reorder(line, opts.rtl))
+            .collect()
+    } else {
+        text_lines
+    };
+    // without the bidi algorithm, reversal is the visual order of purely RTL text
+    #[cfg(not(feature = "bidi"))]
+    let text_lines: Vec<String> = if opts.rtl {
+        text_lines
+            .iter()
+            .map(|line| line.graphemes(true).rev().collect())
+            .collect()
+    } else {
+        text_lines
+    };
+
+    let mut h = 0;
+    let mut w = 0;
+    let mut lines = 0;
+
+    // with a leader, "key\tvalue" puts value flush right, the gap filled with the leader
+    let leader = opts.leader.as_deref().map(|leader| {
+        let fill = if leader.is_empty() { " " } else { leader };
+        // the spacing follows each repetition of the leader, each starts on a whole pixel
+        let width = layout_line(&fonts, scale, spacing, fill, |_, _| {}).ceil() as i32 + spacing;
+        (leader, width.max(0) as u32)
+    });
+
+    // blank lines may take a different height, e.g. for paragraph spacing
+    let line_heights: Vec<i32> = text_lines
+        .iter()
+        .map(|line| match opts.blank_line_height {
+            Some(blank_line_height) if opts.cell_height.is_none() && line.trim().is_empty() => {
+                blank_line_height
+            }
+            _ => line_height,
+        })
+        .collect();
+
+    for (line, line_height) in text_lines.iter().zip(&line_heights) {
+        let lw = match (leader.and(line.split_once('\t')), leader) {
+            (Some((key, value)), Some((_, leader_width))) => {
+                text_width(&fonts, scale, spacing, key)
+                    + leader_width
+                    + text_width(&fonts, scale, spacing, value)
+            }
+            _ => text_width(&fonts, scale, spacing, line),
+        };
+        w = w.max(lw);
+        h += line_height;
+        lines += 1;
+    }
+    w += opts.origin_x.max(0) as u32;
+    // before the byte alignment, so it doesn't swallow the right padding
+    w += (pad_left + pad_right + extra_width) as u32;
+    // key and value lines span `max_width`, the value against its right margin
+    if let (Some(_), Some(max_width)) = (leader, opts.max_width) {
+        w = w.max(max_width);
+    }
+    if let Some(cell_height) = opts.cell_height {
+        h = cell_height * lines;
+    } else {
+        h += opts.line_spacing * (lines - 1);
+    }
+    // grow the canvas so the extreme glyph offsets don't clip
+    let offset_top = -opts.offsets.iter().copied().min().unwrap_or(0).min(0);
+    let offset_bottom = opts.offsets.iter().copied().max().unwrap_or(0).max(0);
+    h += offset_top + offset_bottom + bold;
+
+    // place the text block in a fixed canvas, clipping at the bottom if it doesn't fit
+    let mut block_top = pad_top;
+    if let Some(canvas_height) = opts.canvas_height {
+        let inner_height = canvas_height as i32 - pad_top - pad_bottom;
+        block_top += match opts.valign {
+            VAlign::Top => 0,
+            VAlign::Middle => (inner_height - h) / 2,
+            VAlign::Bottom => inner_height - h,
+        }
+        .max(0);
+        h = canvas_height as i32;
+    } else {
+        h += pad_top + pad_bottom;
+    }
+
+    // align to byte
+    let content_width = w;
+    w = opts.align_mode.align(w);
+    if let Some(max_width) = opts.max_width {
+        // a single word wider than max_width is clipped
+        w = w.min(max_width.div_ceil(8) * 8);
+    }
+    let content_width = content_width.min(w);
+    // a negative `line_spacing` can pull the lines above the top
+    h = h.max(1);
+
+    Ok(f(Layout {
+        fonts,
+        scale,
+        spacing,
+        font_ascent,
+        descent,
+        ascent_shift,
+        line_height,
+        text_lines,
+        line_heights,
+        leader,
+        bold,
+        slant,
+        extra_width,
+        pad_left,
+        pad_right,
+        offset_top,
+        block_top,
+        width: w,
+        height: h,
+        content_width,
+        missing_glyphs,
+    }))
+}
+
+/// A text laid out on a canvas, see `with_layout`
+struct Layout<'a> {
+    fonts: Vec<FontRef<'a>>,
+    scale: PxScale,
+    spacing: i32,
+    font_ascent: f32,
+    descent: f32,
+    /// glyphs are drawn this far above the top of their line
+    ascent_shift: i32,
+    line_height: i32,
+    text_lines: Vec<String>,
+    /// of each line, blank lines may differ
+    line_heights: Vec<i32>,
+    /// the leader to repeat and its width
+    leader: Option<(&'a str, u32)>,
+    bold: i32,
+    slant: f32,
+    /// bold strokes and the italic shear sticking out to the right of the advance width
+    extra_width: i32,
+    pad_left: i32,
+    pad_right: i32,
+    /// room above the text for negative `offsets`
+    offset_top: i32,
+    /// top of the text block in the canvas
+    block_top: i32,
+    /// size of the canvas, before rotation
+    width: u32,
+    height: i32,
+    content_width: u32,
+    missing_glyphs: Vec<char>,
+}
+
+impl Layout<'_> {
+    /// Size of the finished image: rotated, cropped to the fit box when the text doesn't
+    /// fit even at the smallest size, and aligned to byte again
+    fn size(&self, opts: &TextImageOptions) -> (u32, u32) {
+        let (w, h) = match opts.rotate {
+            90 | 270 => (self.height as u32, self.width),
+            _ => (self.width, self.height as u32),
+        };
+        let w = opts
+            .fit_width
+            .map_or(w, |fit_width| w.min(opts.align_mode.align(fit_width)));
+        let h = opts.fit_height.map_or(h, |fit_height| h.min(fit_height));
+        (opts.align_mode.align(w), h)
+    }
+}
+
+fn render(opts: &TextImageOptions) -> Result<TextImage, TextImageError> {
+    with_layout(opts, |layout| draw(opts, layout))
+}
+
+/// Size of the image `render` gives the same options, without drawing it
+fn measure(opts: &TextImageOptions) -> Result<(u32, u32), TextImageError> {
+    with_layout(opts, |layout| layout.size(opts))
+}
+
+/// Draw a laid out text and convert it to the output depth
+fn draw(opts: &TextImageOptions, layout: Layout) -> TextImage {
+    let (w_final, h_final) = layout.size(opts);
+    let Layout {
+        fonts,
+        scale,
+        spacing,
+        font_ascent,
+        descent,
+        ascent_shift,
+        line_height,
+        text_lines,
+        line_heights,
+        leader,
+        bold,
+        slant,
+        extra_width,
+        pad_left,
+        pad_right,
+        offset_top,
+        block_top,
+        width: w,
+        height: h,
+        content_width,
+        missing_glyphs,
+    } = layout;
+
+    let mut image: image::ImageBuffer<Luma<u8>, Vec<u8>> = GrayImage::new(w as _, h as _);
+
+    let (background, luma) = if opts.inverse {
+        (0xFF, 0x00)
+    } else {
+        (0x00, 0xFF)
+    };
+    let background = opts.background.unwrap_or(background);
+    let luma = opts.foreground.unwrap_or(luma);
+    if background != 0x00 {
+        image.fill(background);
+    }
+
+    // decoration lines, as rows relative to the baseline, sized by the font metrics
+    let thickness = (-descent / 4.0).round().max(1.0) as i32;
+    let mut decorations = vec![];
+    if opts.underline {
+        decorations.push((-descent / 2.0).round() as i32);
+    }
+    if opts.strikethrough {
+        // x-height, measured from the outline of `x`
+        let sfont = fonts[0].as_scaled(scale);
+        let x_height = sfont
+            .outline_glyph(sfont.scaled_glyph('x'))
+            .map(|outlined| -outlined.px_bounds().min.y)
+            .unwrap_or(font_ascent / 2.0);
+        decorations.push(-(x_height / 2.0).round() as i32 - thickness / 2);
+    }
+
+    let strokes: &[(i32, i32)] = if opts.bold {
+        &[(0, 0), (1, 0), (0, 1)]
+    } else {
+        &[(0, 0)]
+    };
+    let mut draw = |x: i32, y: i32, text: &str, offsets: &[i32]| {
+        for (dx, dy) in strokes {
+            draw_text(
+                &mut image,
+                luma,
+                x + dx,
+                y + dy,
+                &fonts,
+                scale,
+                spacing,
+                text,
+                offsets,
+                slant,
+                opts.coverage_gamma,
+            );
+        }
+        // only as wide as the text, not the canvas
+        let width = text_width(&fonts, scale, spacing, text) as i32 + bold;
+        let baseline = y + font_ascent.round() as i32;
+        for top in decorations.iter().map(|row| baseline + row) {
+            for py in top..top + thickness {
+                for px in x..x + width {
+                    if (0..image.width() as i32).contains(&px)
+                        && (0..image.height() as i32).contains(&py)
+                    {
+                        image.put_pixel(px as u32, py as u32, Luma([luma]));
+                    }
+                }
+            }
+        }
+    };
+
+    for (i, line) in text_lines.iter().enumerate() {
+        let y = if let Some(cell_height) = opts.cell_height {
+            let cell_top = cell_height * (i as i32);
+            match opts.valign {
+                VAlign::Top => cell_top,
+                VAlign::Middle => cell_top + (cell_height - line_height) / 2,
+                VAlign::Bottom => cell_top + cell_height - line_height,
+            }
+        } else {
+            line_heights[..i].iter().sum::<i32>() + opts.line_spacing * (i as i32)
+        };
+        let y = y + opts.origin_y - ascent_shift + offset_top + block_top;
+
+        if let (Some((key, value)), Some((leader, leader_width))) =
+            (leader.and(line.split_once('\t')), leader)
+        {
+            // the value keeps the same margin to the right as the key to the left
+            let key_end = pad_left + opts.origin_x + text_width(&fonts, scale, spacing, key) as i32;
+            let value_x = w as i32
+                - pad_right
+                - extra_width
+                - opts.origin_x
+                - text_width(&fonts, scale, spacing, value) as i32;
+            draw(pad_left + opts.origin_x, y, key, &opts.offsets);
+            draw(value_x, y, value, &opts.offsets);
+            if !leader.is_empty() && leader_width > 0 {
+                // as many whole leaders as fit, flush against the value
+                let n = (value_x - key_end).max(0) / leader_width as i32;
+                for k in 1..=n {
+                    draw(value_x - k * leader_width as i32, y, leader, &[]);
+                }
+            }
+            continue;
+        }
+
+        // aligned in the final, byte aligned width, RTL lines start at the right edge
+        let line_width = text_width(&fonts, scale, spacing, line) as i32 + extra_width;
+        let align = match opts.align {
+            HAlign::Left if opts.rtl => HAlign::Right,
+            align => align,
+        };
+        let x = match align {
+            HAlign::Left => pad_left + opts.origin_x,
+            HAlign::Center => pad_left + (w as i32 - pad_left - pad_right - line_width) / 2,
+            HAlign::Right => w as i32 - pad_right - opts.origin_x - line_width,
+        };
+        draw(x, y, line, &opts.offsets);
+    }
+
+    // rotate the finished image, then align the new width to byte again
+    let content_width = match opts.rotate {
+        90 | 270 => h as u32,
+        _ => content_width,
+    };
+    let image = match opts.rotate {
+        90 => imageops::rotate90(&image),
+        180 => imageops::rotate180(&image),
+        270 => imageops::rotate270(&image),
+        _ => image,
+    };
+    // cropped or widened to the size measured, the image is clipped to the canvas
+    let (w, h) = (w_final, h_final);
+    let image = if (w, h) == image.dimensions() {
+        image
+    } else {
+        let mut canvas = GrayImage::from_pixel(w, h, Luma([background]));
+        imageops::replace(&mut canvas, &image, 0, 0);
+        canvas
+    };
+
+    let mut raw = image.into_raw();
+
+    // gamma transform
+    if opts.gamma != 1.0 {
+        let gamma = opts.gamma;
+        for p in raw.iter_mut() {
+            let v = (*p as f32 / 255.0).powf(gamma) * 255.0;
+            *p = v as u8;
+        }
+    }
+
+    // the packing keeps the high bits, put the nearest level there
+    if opts.rounding && matches!(opts.gray_depth, 2 | 4) {
+        let levels = (1u16 << opts.gray_depth) - 1;
+        for p in raw.iter_mut() {
+            let level = (*p as u16 * levels + 127) / 255;
+            *p = (level << (8 - opts.gray_depth)) as u8;
+        }
+    }
+
+    if opts.dither && matches!(opts.gray_depth, 2 | 4) {
+        let mut image = GrayImage::from_raw(w, h, raw).unwrap();
+        imageops::dither(&mut image, &GrayLevels(opts.gray_depth));
+        raw = image.into_raw();
+    }
+
+    // convert depth
+    let raw: Vec<u8> = match opts.gray_depth {
+        8 => raw,
+        2 | 4 => pack_gray(&raw, w, opts.gray_depth, background),
+        1 => opts.scan.pack(w, h, |x, y| {
+            let val = raw[(y * w + x) as usize];
+            if opts.dither_edges {
+                val > bayer_threshold(x, y)
+            } else {
+                val >= opts.threshold
+            }
+        }),
+        _ => unreachable!(),
+    };
+
+    TextImage {
+        width: w,
+        height: h,
+        data: raw,
+        content_width: content_width.min(w),
+        missing_glyphs,
+        clipped: false,
+    }
+}
+
+/// Pack 8-bit luma rows of `w` pixels to `depth` bits per pixel, keeping the high bits, the
+/// leftmost pixel in the high bits of a byte.
+///
+/// Each row starts on a byte boundary, a row ending inside a byte is padded with `fill`.
+pub fn pack_gray(raw: &[u8], w: u32, depth: u8, fill: u8) -> Vec<u8> {
+    let per_byte = (8 / depth) as usize;
+    let mut ret = Vec::with_capacity(raw.len() / per_byte + 1);
+    for row in raw.chunks(w as usize) {
+        for ch in row.chunks(per_byte) {
+            ret.push((0..per_byte).fold(0, |n, i| {
+                let p = ch.get(i).copied().unwrap_or(fill) >> (8 - depth);
+                n | p << (8 - depth * (i as u8 + 1))
+            }));
+        }
+    }
+    ret
+}
+
+/// Evenly spaced gray levels of a bit depth, for dithering.
+///
+/// Each level maps to a luma whose high bits are the level, as kept by the packing.
+struct GrayLevels(u8);
+
+impl imageops::colorops::ColorMap for GrayLevels {
+    type Color = Luma<u8>;
+
+    fn index_of(&self, color: &Luma<u8>) -> usize {
+        let levels = (1usize << self.0) - 1;
+        (color.0[0] as usize * levels + 127) / 255
+    }
+
+    fn lookup(&self, index: usize) -> Option<Luma<u8>> {
+        let levels = (1usize << self.0) - 1;
+        (index <= levels).then(|| Luma([(index * 255 / levels) as u8]))
+    }
+
+    fn has_lookup(&self) -> bool {
+        true
+    }
+
+    fn map_color(&self, color: &mut Luma<u8>) {
+        *color = self.lookup(self.index_of(color)).unwrap();
+    }
+}
+
+/// Lay out a single line of text with its top at y = 0, calling `f` with the index of the
+/// grapheme cluster and each of its outlined glyphs.
+///
+/// Each cluster is drawn with the first of `fonts` that has glyphs for it, see
+/// `font_for_cluster`, on the baseline of the tallest of them.
+///
+/// `letter_spacing` is added between clusters, not after the last one, so a char keeps its
+/// combining marks. A negative spacing never moves a cluster to the left of the previous one.
+///
+/// Returns the advance width of the line.
+fn layout_line<F: Font>(
+    fonts: &[F],
+    scale: PxScale,
+    letter_spacing: i32,
+    text: &str,
+    mut f: impl FnMut(usize, OutlinedGlyph),
+) -> f32 {
+    let (ascent, _, _) = line_metrics(fonts, scale);
+    let mut x = 0.0;
+    let mut last: Option<(usize, GlyphId, f32)> = None;
+
+    for (i, cluster) in text.graphemes(true).enumerate() {
+        let font = font_for_cluster(fonts, cluster);
+        let sfont = fonts[font].as_scaled(scale);
+        for (j, c) in cluster.chars().enumerate() {
+            let glyph_id = sfont.glyph_id(c);
+            if let Some((last_font, last, last_x)) = last {
+                // kerning pairs only exist within a font
+                if last_font == font {
+                    x += sfont.kern(last, glyph_id);
+                }
+                if j == 0 {
+                    x += letter_spacing as f32;
+                    x = x.max(last_x);
+                }
+            }
+            let glyph = glyph_id.with_scale_and_position(scale, point(x, ascent));
+            last = Some((font, glyph_id, x));
+            x += sfont.h_advance(glyph_id);
+            if let Some(outlined) = sfont.outline_glyph(glyph) {
+                f(i, outlined);
+            }
+        }
+    }
+
+    x
+}
+
+/// Top and bottom pixel rows covered by the glyphs of `text`, laid out as by `layout_line`,
+/// or `None` if no char has an outline
+fn glyph_extent<F: Font>(fonts: &[F], scale: PxScale, text: &str) -> Option<(f32, f32)> {
+    let mut extent: Option<(f32, f32)> = None;
+    for line in text.lines() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        layout_line(fonts, scale, 0, line, |_, glyph| {
+            let bb = glyph.px_bounds();
+            extent = Some(match extent {
+                Some((top, bottom)) => (top.min(bb.min.y), bottom.max(bb.max.y)),
+                None => (bb.min.y, bb.max.y),
+            });
+        });
+    }
+    extent
+}
+
+/// Greedily break a line into lines of at most `max_width` pixels.
+///
+/// Breaks happen at whitespace, which is dropped at the break, and before or after any CJK
+/// character, never inside a grapheme cluster. A word wider than `max_width` is kept on a
+/// line of its own.
+fn wrap_line<F: Font>(
+    fonts: &[F],
+    scale: PxScale,
+    letter_spacing: i32,
+    line: &str,
+    max_width: u32,
+) -> Vec<String> {
+    // segments a break may happen before, each is leading whitespace and a word or CJK char
+    let mut segments: Vec<String> = vec![];
+    let mut prev: Option<char> = None;
+    for cluster in line.graphemes(true) {
+        // a cluster breaks like its first char, e.g. the base of combining marks
+        let c = cluster.chars().next().unwrap();
+        let breaks = match prev {
+            None => true,
+            Some(prev) => is_cjk(c) || is_cjk(prev) || (prev.is_whitespace() && !c.is_whitespace()),
+        };
+        if breaks && !(c.is_whitespace() && prev.is_some_and(char::is_whitespace)) {
+            segments.push(String::new());
+        }
+        segments.last_mut().unwrap().push_str(cluster);
+        prev = Some(c);
+    }
+
+    let mut lines = vec![];
+    let mut current = String::new();
+    for segment in segments {
+        let candidate = format!("{}{}", current, segment);
+        if !current.is_empty()
+            && text_width(fonts, scale, letter_spacing, candidate.trim_end()) > max_width
+        {
+            lines.push(current.trim_end().to_string());
+            current = segment.trim_start().to_string();
+        } else {
+            current = candidate;
+        }
+    }
+    lines.push(current.trim_end().to_string());
+    lines
+}
+
+/// Replace each tab with spaces up to the next multiple of `tab_width` columns, counting
+/// one column per grapheme cluster
+fn expand_tabs(line: &str, tab_width: u32) -> String {
+    let tab_width = tab_width.max(1) as usize;
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    for cluster in line.graphemes(true) {
+        if cluster == "\t" {
+            let spaces = tab_width - column % tab_width;
+            expanded.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
+        } else {
+            expanded.push_str(cluster);
+            column += 1;
+        }
+    }
+    expanded
+}
+
+/// Whether `c` is a CJK character, which lines may break before or after
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{30FF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{AC00}'..='\u{D7AF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FF00}'..='\u{FFEF}'
+        | '\u{20000}'..='\u{2FFFF}')
+}
+
+/// Width of a single line of text, in whole pixels
+fn text_width<F: Font>(fonts: &[F], scale: PxScale, letter_spacing: i32, text: &str) -> u32 {
+    layout_line(fonts, scale, letter_spacing, text, |_, _| {}) as u32
+}
+
+/// Index of the first of `fonts` with a glyph for `c`, or of the first font if none has.
+///
+/// A glyph without an outline only counts for whitespace, some fonts map chars they don't
+/// support to an empty glyph.
+fn font_for<F: Font>(fonts: &[F], c: char) -> usize {
+    fonts
+        .iter()
+        .position(|font| has_glyph(font, c))
+        .unwrap_or(0)
+}
+
+/// Whether `font` has a glyph to draw `c` with
+fn has_glyph<F: Font>(font: &F, c: char) -> bool {
+    let glyph_id = font.glyph_id(c);
+    glyph_id.0 != 0 && (c.is_whitespace() || font.outline(glyph_id).is_some())
+}
+
+/// Index of the first of `fonts` with glyphs for every char of a grapheme cluster, or the
+/// font of its first char if none has, so combining marks are set in the font of their base.
+fn font_for_cluster<F: Font>(fonts: &[F], cluster: &str) -> usize {
+    let mut chars = cluster.chars();
+    let Some(base) = chars.next() else {
+        return 0;
+    };
+    fonts
+        .iter()
+        .position(|font| has_glyph(font, base) && chars.clone().all(|c| font.glyph_id(c).0 != 0))
+        .unwrap_or_else(|| font_for(fonts, base))
+}
+
+/// Ascent, descent and line gap of a line set in `fonts`, the extremes over all of them
+fn line_metrics<F: Font>(fonts: &[F], scale: PxScale) -> (f32, f32, f32) {
+    fonts
+        .iter()
+        .map(|font| {
+            let sfont = font.as_scaled(scale);
+            (sfont.ascent(), sfont.descent(), sfont.line_gap())
+        })
+        .reduce(|a, b| (a.0.max(b.0), a.1.min(b.1), a.2.max(b.2)))
+        .unwrap_or_default()
+}
+
+/// Threshold of the 4x4 Bayer matrix at a pixel, from 8 to 248.
+///
+/// Full coverage (255) is always above and no coverage (0) always below, so only
+/// partially covered edge pixels are dithered.
+fn bayer_threshold(x: u32, y: u32) -> u8 {
+    const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+    BAYER[(y % 4) as usize][(x % 4) as usize] * 16 + 8
+}
+
+/// Quantize glyph coverage to an 8-bit blend weight, after applying `gamma` to it.
+///
+/// All glyph coverage goes through here rather than the blending of the rendering
+/// dependencies, so the output bytes stay stable across their patch releases.
+/// Full and zero coverage are unchanged by the gamma, only edges and thin strokes are.
+fn quantize_coverage(coverage: f32, gamma: f32) -> u8 {
+    let coverage = coverage.clamp(0.0, 1.0);
+    let coverage = if gamma != 1.0 {
+        coverage.powf(gamma)
+    } else {
+        coverage
+    };
+    (coverage * 255.0).round() as u8
+}
+
+/// Horizontal shift per pixel of height of synthetic italic
+pub const ITALIC_SLANT: f32 = 0.2;
+
+/// Blend `fg` over `bg` with an 8-bit weight, rounding to nearest
+fn blend(bg: u8, fg: u8, weight: u8) -> u8 {
+    let weight = weight as u32;
+    ((bg as u32 * (255 - weight) + fg as u32 * weight + 127) / 255) as u8
+}
+
+/// Draw a single line of text with its top-left at (x, y), in luma `fg`, each glyph moved
+/// down by the cycled `offsets`, with `coverage_gamma` applied to the glyph coverage.
+///
+/// Each pixel row is shifted right by `slant` times its height above the descent line, a
+/// shear of whole pixels that keeps the coverage values unchanged.
+#[allow(clippy::too_many_arguments)]
+fn draw_text<F: Font>(
+    image: &mut GrayImage,
+    fg: u8,
+    x: i32,
+    y: i32,
+    fonts: &[F],
+    scale: PxScale,
+    letter_spacing: i32,
+    text: &str,
+    offsets: &[i32],
+    slant: f32,
+    coverage_gamma: f32,
+) {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    let (ascent, descent, _) = line_metrics(fonts, scale);
+    let bottom = ascent - descent;
+
+    layout_line(fonts, scale, letter_spacing, text, |i, glyph| {
+        let bb = glyph.px_bounds();
+        let offset = if offsets.is_empty() {
+            0
+        } else {
+            offsets[i % offsets.len()]
+        };
+        glyph.draw(|gx, gy, coverage| {
+            let row = bb.min.y.round() + gy as f32;
+            let shear = (slant * (bottom - row - 0.5)).round() as i32;
+            let px = x + shear + bb.min.x.round() as i32 + gx as i32;
+            let py = y + offset + row as i32;
+            if (0..width).contains(&px) && (0..height).contains(&py) {
+                let pixel = image.get_pixel_mut(px as u32, py as u32);
+                pixel.0[0] = blend(pixel.0[0], fg, quantize_coverage(coverage, coverage_gamma));
+            }
+        });
+    });
+}
+
+/// Reorder a line into visual order per the Unicode Bidi Algorithm, with a right-to-left
+/// base direction if `rtl`, or the direction of the first strong character otherwise.
+#[cfg(feature = "bidi")]
+fn bidi_reorder(line: &str, rtl: bool) -> String {
+    let level = rtl.then(unicode_bidi::Level::rtl);
+    let info = unicode_bidi::BidiInfo::new(line, level);
+    info.paragraphs
+        .iter()
+        .map(|para| info.reorder_line(para, para.range.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FONT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/DejaVuSans.ttf");
+
+    fn opts(text: &str) -> TextImageOptions {
+        TextImageOptions {
+            text: text.to_string(),
+            font: FONT.into(),
+            ..Default::default()
+        }
+    }
+
+    fn render_text(opts: TextImageOptions) -> TextImage {
+        TextImageBuilder::from(opts).build().unwrap()
+    }
+
+    /// Rows `y0..y1` of a Gray8 image
+    fn rows(image: &TextImage, y0: u32, y1: u32) -> &[u8] {
+        &image.data[(y0 * image.width) as usize..(y1 * image.width) as usize]
+    }
+
+    /// Rows of a Gray8 image with any pixel set
+    fn ink_rows(image: &TextImage) -> Vec<u32> {
+        (0..image.height)
+            .filter(|&y| rows(image, y, y + 1).iter().any(|&p| p != 0))
+            .collect()
+    }
+
+    /// 10x3 image with the pixels of the first column and the diagonal set
+    fn pattern(x: u32, y: u32) -> bool {
+        x == 0 || x == y
+    }
+
+    #[test]
+    fn pack_gray_odd_widths() {
+        for w in 1..=9u32 {
+            let raw = vec![0xFF; w as usize * 3];
+            for depth in [1, 2, 4, 8] {
+                let stride = (w * depth as u32).div_ceil(8) as usize;
+                let packed = pack_gray(&raw, w, depth, 0);
+                assert_eq!(packed.len(), stride * 3, "w {} depth {}", w, depth);
+                // the last pixel of each row is kept, the padding after it is `fill`
+                let last_bit = 7 - (w as usize * depth as usize - 1) % 8;
+                for row in packed.chunks(stride) {
+                    assert_eq!(row[stride - 1] >> last_bit, 0xFF >> last_bit);
+                    assert_eq!(row[stride - 1] & ((1 << last_bit) - 1), 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn render_sizes() {
+        for depth in [1, 2, 4, 8] {
+            let image = render_text(TextImageOptions {
+                gray_depth: depth,
+                ..opts("Hello")
+            });
+            assert_eq!(image.width % 8, 0);
+            assert!(image.content_width <= image.width);
+            let stride = (image.width * depth as u32).div_ceil(8);
+            assert_eq!(image.data.len() as u32, stride * image.height);
+            assert!(image.data.iter().any(|&b| b != 0));
+        }
+    }
+
+    #[test]
+    fn render_inverse() {
+        let image = render_text(TextImageOptions {
+            inverse: true,
+            ..opts(".")
+        });
+        assert_eq!(image.data[0], 0xFF);
+    }
+
+    #[test]
+    fn measure_matches_render() {
+        let cases = [
+            opts("Hello, world"),
+            TextImageOptions {
+                rotate: 90,
+                align_mode: AlignMode::Nearest,
+                ..opts("Hello")
+            },
+            TextImageOptions {
+                padding: [1, 3, 2, 5],
+                canvas_height: Some(40),
+                valign: VAlign::Bottom,
+                italic: true,
+                ..opts("a\n\nb")
+            },
+            TextImageOptions {
+                max_width: Some(30),
+                line_spacing: -40,
+                ..opts("one two three")
+            },
+            TextImageOptions {
+                fit_width: Some(40),
+                fit_height: Some(12),
+                rotate: 270,
+                ..opts("too long to fit")
+            },
+            TextImageOptions {
+                fit_width: Some(64),
+                ..opts("Hello, world")
+            },
+        ];
+        for opts in cases {
+            let image = render_text(opts.clone());
+            let size = text_image_size(&opts).unwrap();
+            assert_eq!(size, (image.width, image.height), "{:?}", opts);
+        }
+    }
+
+    /// The exact bytes of a rendering, which must only change with this crate, not with the
+    /// patch releases of the rendering dependencies
+    #[test]
+    fn golden_output() {
+        for (depth, golden) in [
+            (1, &include_bytes!("../tests/fixtures/hello-gray1.bin")[..]),
+            (4, &include_bytes!("../tests/fixtures/hello-gray4.bin")[..]),
+        ] {
+            let image = render_text(TextImageOptions {
+                font_size: 16.0,
+                gray_depth: depth,
+                ..opts("Hello, world!\nAVATAR fi")
+            });
+            assert_eq!((image.width, image.height), (88, 32));
+            assert!(image.data == golden, "gray depth {} output changed", depth);
+        }
+    }
+
+    #[test]
+    fn cell_height_rows() {
+        // a tall accented capital with a descender, and a short letter
+        let image = render_text(TextImageOptions {
+            gray_depth: 8,
+            cell_height: Some(24),
+            ..opts("Ég\nÉg\nx\nÉg")
+        });
+        assert_eq!(image.height, 4 * 24);
+        // the same line is drawn the same in each cell, starting at `i * cell_height`
+        let cell = |i: u32| rows(&image, i * 24, (i + 1) * 24);
+        assert_eq!(cell(0), cell(1));
+        assert_eq!(cell(0), cell(3));
+        assert_ne!(cell(0), cell(2));
+        // lines share the baseline inside their cells, the short letter starts lower
+        let ink = ink_rows(&image);
+        let top_in_cell = |i: u32| ink.iter().find(|&&y| y / 24 == i).unwrap() - i * 24;
+        assert!(top_in_cell(2) > top_in_cell(0));
+    }
+
+    #[cfg(feature = "bidi")]
+    #[test]
+    fn bidi_visual_order() {
+        // the Hebrew run is reversed in place, the Latin one kept
+        assert_eq!(bidi_reorder("ab אבג", false), "ab גבא");
+        // with a right-to-left base, the runs swap as well
+        assert_eq!(bidi_reorder("ab אבג", true), "גבא ab");
+        let image = render_text(TextImageOptions {
+            bidi: true,
+            ..opts("ab אבג")
+        });
+        assert_eq!(image, render_text(opts("ab גבא")));
+    }
+
+    #[test]
+    fn align_mode() {
+        assert_eq!(AlignMode::Up.align(1), 8);
+        assert_eq!(AlignMode::Up.align(8), 8);
+        assert_eq!(AlignMode::Up.align(9), 16);
+        assert_eq!(AlignMode::Nearest.align(11), 8);
+        assert_eq!(AlignMode::Nearest.align(12), 16);
+        assert_eq!(AlignMode::Nearest.align(2), 8);
+        assert_eq!("nearest".parse(), Ok(AlignMode::Nearest));
+    }
+
+    #[test]
+    fn max_ascent_clips_tall_glyphs() {
+        let full = render_text(TextImageOptions {
+            gray_depth: 8,
+            ..opts("Éx")
+        });
+        let clamped = render_text(TextImageOptions {
+            gray_depth: 8,
+            max_ascent: Some(8.0),
+            ..opts("Éx")
+        });
+        assert!(clamped.height < full.height);
+        // the baseline moves up to the clamped ascent, below the last row with ink
+        let baseline = |image: &TextImage| ink_rows(image).last().unwrap() + 1;
+        assert_eq!(baseline(&clamped), 8);
+        // the glyphs are drawn as they are, the top of the accented capital is cut off
+        let top = baseline(&full) - 8;
+        assert_eq!(rows(&clamped, 0, 8), rows(&full, top, top + 8));
+        assert!(rows(&full, 0, top).iter().any(|&p| p != 0));
+    }
+
+    #[test]
+    fn leader_fills_max_width() {
+        let width = |text: &str| render_text(opts(text)).content_width;
+        let image = render_text(TextImageOptions {
+            gray_depth: 8,
+            leader: Some(".".into()),
+            max_width: Some(96),
+            ..opts("A\tB")
+        });
+        assert_eq!(image.width, 96);
+        // B is drawn as on its own, against the right edge
+        let b = render_text(TextImageOptions {
+            gray_depth: 8,
+            ..opts("B")
+        });
+        let bw = b.content_width;
+        assert_eq!(image.height, b.height);
+        for y in 0..image.height {
+            assert_eq!(
+                rows(&image, y, y + 1)[(96 - bw) as usize..],
+                rows(&b, y, y + 1)[..bw as usize]
+            );
+        }
+        // whole dots fill the gap between A and B
+        let dot_row = *ink_rows(&image).last().unwrap();
+        let gap = &rows(&image, dot_row, dot_row + 1)[width("A") as usize..(96 - bw) as usize];
+        let dots: Vec<usize> = (1..gap.len())
+            .filter(|&x| gap[x - 1] == 0 && gap[x] != 0)
+            .collect();
+        let step = dots[1] - dots[0];
+        assert!(step as u32 >= width("."));
+        assert!(dots.windows(2).all(|d| d[1] - d[0] == step));
+        assert_eq!(dots.len(), gap.len() / step);
+    }
+
+    #[test]
+    fn scan_row_msb() {
+        assert_eq!(
+            Scan::RowMsb.pack(10, 3, pattern),
+            [0x80, 0x00, 0xC0, 0x00, 0xA0, 0x00]
+        );
+    }
+
+    #[test]
+    fn scan_row_lsb() {
+        assert_eq!(
+            Scan::RowLsb.pack(10, 3, pattern),
+            [0x01, 0x00, 0x03, 0x00, 0x05, 0x00]
+        );
+    }
+
+    #[test]
+    fn scan_col_msb() {
+        let data = Scan::ColMsb.pack(10, 3, pattern);
+        assert_eq!(data.len(), 10);
+        assert_eq!(&data[..4], [0xE0, 0x40, 0x20, 0x00]);
+    }
+
+    #[test]
+    fn scan_col_lsb() {
+        let data = Scan::ColLsb.pack(10, 3, pattern);
+        assert_eq!(data.len(), 10);
+        assert_eq!(&data[..4], [0x07, 0x02, 0x04, 0x00]);
+    }
+
+    #[test]
+    fn scan_page_vertical() {
+        // 2 pages of 10 columns, the second holding rows 8 and 9
+        let data = Scan::PageVertical.pack(10, 10, pattern);
+        assert_eq!(data.len(), 20);
+        assert_eq!(&data[..3], [0xFF, 0x02, 0x04]);
+        assert_eq!(&data[10..], [0x03, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn scan_from_str() {
+        assert_eq!("page_vertical".parse(), Ok(Scan::PageVertical));
+        assert!("row".parse::<Scan>().is_err());
+        assert!(Scan::RowMsb.is_image_raw());
+        assert!(!Scan::ColMsb.is_image_raw());
+    }
+
+    #[test]
+    fn dither_edges_keeps_interiors() {
+        let text = || TextImageOptions {
+            font_size: 48.0,
+            ..opts("Ho")
+        };
+        let gray = render_text(TextImageOptions {
+            gray_depth: 8,
+            ..text()
+        });
+        let dithered = render_text(TextImageOptions {
+            dither_edges: true,
+            ..text()
+        });
+        assert_eq!((dithered.width, dithered.height), (gray.width, gray.height));
+        let stride = gray.width.div_ceil(8) as usize;
+        let bit = |i: usize| {
+            let (x, y) = (i % gray.width as usize, i / gray.width as usize);
+            dithered.data[y * stride + x / 8] & (0x80 >> (x % 8)) != 0
+        };
+        let (mut edges_on, mut edges_off) = (0, 0);
+        for (i, &coverage) in gray.data.iter().enumerate() {
+            match coverage {
+                0xFF => assert!(bit(i), "interior pixel {} not set", i),
+                0 => assert!(!bit(i), "blank pixel {} set", i),
+                _ if bit(i) => edges_on += 1,
+                _ => edges_off += 1,
+            }
+        }
+        // the anti-aliased edges become a pattern, not all on or off
+        assert!(edges_on > 0 && edges_off > 0);
+    }
+
+    #[test]
+    fn blank_line_height() {
+        let line_height = render_text(opts("a")).height;
+        let full = render_text(TextImageOptions {
+            gray_depth: 8,
+            ..opts("a\n\nb")
+        });
+        let short = render_text(TextImageOptions {
+            gray_depth: 8,
+            blank_line_height: Some(4),
+            ..opts("a\n\nb")
+        });
+        assert_eq!(full.height, 3 * line_height);
+        assert_eq!(full.height - short.height, line_height - 4);
+        // the line after the blank one moves up by as much
+        let last = |image: &TextImage| *ink_rows(image).last().unwrap();
+        assert_eq!(last(&full) - last(&short), line_height - 4);
+    }
+
+    #[test]
+    fn offsets_grow_canvas() {
+        let render = |offsets: Vec<i32>| {
+            render_text(TextImageOptions {
+                gray_depth: 8,
+                offsets,
+                ..opts("a")
+            })
+        };
+        let base = render(vec![]);
+        let h = base.height;
+        // room for an upward offset is added above the line, so the glyph stays where it was
+        // and is drawn whole, a downward offset adds room below
+        let up = render(vec![-10]);
+        assert_eq!(up.height, h + 10);
+        assert_eq!(rows(&up, 0, h), rows(&base, 0, h));
+        assert!(rows(&up, h, h + 10).iter().all(|&p| p == 0));
+        let down = render(vec![10]);
+        assert_eq!(down.height, h + 10);
+        assert_eq!(rows(&down, 10, h + 10), rows(&base, 0, h));
+        assert!(rows(&down, 0, 10).iter().all(|&p| p == 0));
+        let both = render_text(TextImageOptions {
+            offsets: vec![-10, 10],
+            ..opts("aa")
+        });
+        assert_eq!(both.height, h + 20);
+    }
+
+    #[test]
+    fn glyph_at_origin() {
+        let image = render_text(TextImageOptions {
+            gray_depth: 8,
+            ..opts("H")
+        });
+        // the glyph as laid out by the font, its origin on the baseline at the ascent
+        let data = std::fs::read(FONT).unwrap();
+        let font = FontRef::try_from_slice(&data).unwrap();
+        let scale = PxScale::from(16.0);
+        let glyph = font
+            .glyph_id('H')
+            .with_scale_and_position(scale, point(0.0, font.as_scaled(scale).ascent()));
+        let bb = font.outline_glyph(glyph).unwrap().px_bounds();
+        let ink = |x: u32, y: u32| rows(&image, y, y + 1)[x as usize] != 0;
+        let left = (0..image.width)
+            .find(|&x| (0..image.height).any(|y| ink(x, y)))
+            .unwrap();
+        assert_eq!(left, bb.min.x.round() as u32);
+        assert_eq!(ink_rows(&image)[0], bb.min.y.round() as u32);
+    }
+
+    #[test]
+    fn coverage_gamma_thickens_edges() {
+        let render = |coverage_gamma: f32| {
+            render_text(TextImageOptions {
+                gray_depth: 8,
+                coverage_gamma,
+                ..opts("il/")
+            })
+        };
+        let plain = render(1.0);
+        let thick = render(0.5);
+        let mut raised = 0;
+        // solid pixels stay as they are, partial coverage only goes up
+        for (&p, &t) in plain.data.iter().zip(&thick.data) {
+            assert!(t >= p);
+            if p == 0xFF {
+                assert_eq!(t, p);
+            }
+            raised += (t > p) as u32;
+        }
+        assert!(raised > 0);
+    }
+
+    #[test]
+    fn require_chars() {
+        let build = |require_chars: &str| {
+            TextImageBuilder::from(TextImageOptions {
+                require_chars: require_chars.to_string(),
+                ..opts("a")
+            })
+            .build()
+        };
+        assert!(build("abc").is_ok());
+        // chars only in `require_chars` are checked too, the error lists those missing
+        assert!(matches!(
+            build("b\u{E000}"),
+            Err(TextImageError::MissingGlyphs(_, chars)) if chars == ['\u{E000}']
+        ));
+    }
+
+    #[test]
+    fn render_wraps_max_width() {
+        let wide = render_text(opts("one two three"));
+        let wrapped = render_text(TextImageOptions {
+            max_width: Some(48),
+            ..opts("one two three")
+        });
+        assert!(wrapped.width <= 48);
+        assert!(wrapped.height > wide.height);
+    }
+
+    #[test]
+    fn missing_glyphs() {
+        let image = render_text(opts("a\u{E000}b\u{E001}\u{E000}"));
+        // each char once, in text order
+        assert_eq!(image.missing_glyphs, ['\u{E000}', '\u{E001}']);
+        assert!(render_text(opts("ab")).missing_glyphs.is_empty());
+    }
+
+    #[test]
+    fn render_rotate() {
+        let render = |rotate: u32| {
+            render_text(TextImageOptions {
+                gray_depth: 8,
+                rotate,
+                ..opts("Hi")
+            })
+        };
+        let image = render(0);
+        let (w, h) = (image.width, image.height);
+        let rotated = render(180);
+        assert_eq!((rotated.width, rotated.height), (w, h));
+        assert!(rotated.data.iter().eq(image.data.iter().rev()));
+        // clockwise, the bottom-left corner becomes the top-left
+        let rotated = render(90);
+        assert_eq!((rotated.width, rotated.height), (h, w));
+        for (y, x) in (0..h).flat_map(|y| (0..w).map(move |x| (y, x))) {
+            assert_eq!(
+                rotated.data[(x * h + h - 1 - y) as usize],
+                image.data[(y * w + x) as usize]
+            );
+        }
+    }
+
+    #[test]
+    fn expand_tabs_to_columns() {
+        assert_eq!(expand_tabs("a\tb", 4), "a   b");
+        assert_eq!(expand_tabs("abcd\te", 4), "abcd    e");
+        assert_eq!(expand_tabs("\t", 0), " ");
+        // a cluster is one column
+        assert_eq!(expand_tabs("e\u{301}\tx", 4), "e\u{301}   x");
+    }
+
+    #[test]
+    fn origin_of_old_layout() {
+        let render = |origin_x: i32, origin_y: i32| {
+            render_text(TextImageOptions {
+                gray_depth: 8,
+                origin_x,
+                origin_y,
+                ..opts("Éj")
+            })
+        };
+        let image = render(0, 0);
+        // the old layout drew 1px right and 1px up, cutting off the top row of the accent
+        let old = render(1, -1);
+        assert_eq!(old.height, image.height);
+        assert_eq!(ink_rows(&image)[0], 0);
+        for y in 0..image.height - 1 {
+            let row = &rows(&image, y + 1, y + 2)[..image.width as usize - 1];
+            assert_eq!(&rows(&old, y, y + 1)[1..image.width as usize], row);
+        }
+    }
+
+    #[test]
+    fn row_lsb_reverses_bits() {
+        let msb = render_text(opts("Ag"));
+        let lsb = render_text(TextImageOptions {
+            scan: Scan::RowLsb,
+            ..opts("Ag")
+        });
+        assert_eq!((lsb.width, lsb.height), (msb.width, msb.height));
+        assert!(msb.data.iter().any(|&b| b != b.reverse_bits()));
+        let reversed: Vec<u8> = msb.data.iter().map(|b| b.reverse_bits()).collect();
+        assert_eq!(lsb.data, reversed);
+    }
+
+    #[test]
+    fn render_fit_width() {
+        assert!(render_text(opts("Hello, world")).width > 64);
+        let image = render_text(TextImageOptions {
+            fit_width: Some(64),
+            ..opts("Hello, world")
+        });
+        assert!(image.width <= 64);
+        assert!(!image.clipped);
+    }
+
+    #[test]
+    fn pack_gray_pads_rows() {
+        // 3 pixels per row, 2 rows
+        let raw = [0xF0, 0x80, 0x30, 0x00, 0xFF, 0x40];
+        assert_eq!(pack_gray(&raw, 3, 4, 0), [0xF8, 0x30, 0x0F, 0x40]);
+        assert_eq!(pack_gray(&raw, 3, 2, 0xFF), [0xE3, 0x37]);
+        assert_eq!(pack_gray(&raw, 3, 1, 0), [0xC0, 0x40]);
+        assert_eq!(pack_gray(&raw, 3, 8, 0), raw);
+    }
+
+    #[test]
+    fn render_lines() {
+        let one = render_text(opts("Hi"));
+        let two = render_text(opts("Hi\nHi"));
+        assert_eq!(one.width, two.width);
+        assert!(two.height > one.height);
+        // `\r\n` and a stray `\r` at the end are line breaks as well
+        assert_eq!(render_text(opts("Hi\r\nHi\r")), two);
+    }
+
+    #[test]
+    fn runtime_text_image_matches_builder() {
+        let image = render_text(opts("Hi"));
+        assert_eq!(
+            text_image(&opts("Hi")).unwrap(),
+            (image.width, image.height, image.data)
+        );
+    }
+
+    #[test]
+    fn invalid_heights() {
+        let err = TextImageBuilder::new()
+            .text("a\n\nb")
+            .font_path(FONT)
+            .blank_line_height(-40)
+            .build();
+        assert!(matches!(
+            err,
+            Err(TextImageError::InvalidHeight("blank_line_height", -40))
+        ));
+        let err = TextImageBuilder::from(opts("a")).cell_height(0).build();
+        assert!(matches!(
+            err,
+            Err(TextImageError::InvalidHeight("cell_height", 0))
+        ));
+        let image = TextImageBuilder::from(opts("a\nb\nc"))
+            .line_spacing(-1000)
+            .build()
+            .unwrap();
+        assert_eq!(image.height, 1);
+    }
+
+    #[test]
+    fn read_font_error() {
+        let err = TextImageBuilder::from(TextImageOptions {
+            font: "missing.ttf".into(),
+            ..opts("a")
+        })
+        .build();
+        assert!(matches!(err, Err(TextImageError::ReadFont(..))));
+    }
+}
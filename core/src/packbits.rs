@@ -0,0 +1,108 @@
+//! PackBits run-length compression of image data, for the `compress = "packbits"` option of
+//! the macros.
+//!
+//! The format is described in `text-image-rt`, which has the decompressor without `std`, for
+//! firmware; it is re-exported here.
+//!
+//! ```
+//! use text_image_core::{compress, decompress};
+//!
+//! let data = [0, 0, 0, 0, 0xAA, 0x55, 0xFF, 0xFF, 0xFF];
+//! let packed = compress(&data);
+//! let mut buf = [0; 9];
+//! assert_eq!(decompress(&packed, &mut buf), data.len());
+//! assert_eq!(buf, data);
+//! ```
+
+pub use text_image_rt::decompress;
+
+/// Longest run or literal of a packet
+const MAX_PACKET: usize = 128;
+
+/// Compress `data` with PackBits
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let run_at = |i: usize| {
+        data[i..]
+            .iter()
+            .take(MAX_PACKET)
+            .take_while(|&&b| b == data[i])
+            .count()
+    };
+
+    let mut ret = Vec::with_capacity(data.len() + data.len() / MAX_PACKET + 1);
+    let mut i = 0;
+    while i < data.len() {
+        let run = run_at(i);
+        if run >= 2 {
+            ret.extend([(257 - run) as u8, data[i]]);
+            i += run;
+            continue;
+        }
+        // literal up to the next run worth a packet of its own, pairs cost the same either way
+        let start = i;
+        i += 1;
+        while i < data.len() && i - start < MAX_PACKET && run_at(i) < 3 {
+            i += 1;
+        }
+        ret.push((i - start - 1) as u8);
+        ret.extend_from_slice(&data[start..i]);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let packed = compress(data);
+        let mut buf = vec![0; data.len()];
+        assert_eq!(decompress(&packed, &mut buf), data.len());
+        assert_eq!(buf, data);
+        packed
+    }
+
+    #[test]
+    fn round_trip_edges() {
+        assert!(round_trip(&[]).is_empty());
+        assert_eq!(round_trip(&[7]), [0, 7]);
+        // the longest run is one packet, one more byte starts a literal
+        assert_eq!(round_trip(&[0; 128]), [0x81, 0]);
+        assert_eq!(round_trip(&[0; 129]), [0x81, 0, 0, 0]);
+        assert_eq!(round_trip(&[0; 130]), [0x81, 0, 0xFF, 0]);
+        // literals are split at 128 bytes
+        let alternating: Vec<u8> = (0..300).map(|i| [0x55, 0xAA][i % 2]).collect();
+        let packed = round_trip(&alternating);
+        assert_eq!(packed.len(), 300 + 3);
+        assert_eq!([packed[0], packed[129], packed[258]], [127, 127, 43]);
+        // a run of 2 between literals stays in the literal
+        assert_eq!(round_trip(&[1, 2, 2, 3]), [3, 1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn round_trip_random() {
+        // xorshift, runs of random length between random bytes
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..200 {
+            let len = next() as usize % 1000;
+            let mut data = Vec::with_capacity(len);
+            while data.len() < len {
+                let b = next() as u8;
+                let run = match next() % 4 {
+                    0 => next() as usize % 300,
+                    _ => 1,
+                };
+                data.extend(std::iter::repeat_n(b, run.min(len - data.len()).max(1)));
+            }
+            let packed = round_trip(&data);
+            // never more than one header byte per 128 bytes over the input
+            assert!(packed.len() <= data.len() + data.len().div_ceil(128));
+        }
+    }
+}
@@ -0,0 +1,475 @@
+//! Palette mapping, dithering and 1-bit packing of color images, behind the palette image
+//! macros.
+//!
+//! ```no_run
+//! use text_image_core::{dither, pack_monochrome, ColorMetric, Dither, PaletteMap, Scan};
+//! use text_image_core::BWR_PALETTE;
+//!
+//! let mut im = image::open("logo.png").unwrap().to_rgb8();
+//! let map = PaletteMap {
+//!     palette: BWR_PALETTE.to_vec(),
+//!     metric: ColorMetric::L2,
+//! };
+//! dither(&mut im, &map, Dither::Floyd, 8, None);
+//! let red = pack_monochrome(&im, &map, 2, None, false, Scan::RowMsb);
+//! ```
+
+use std::str::FromStr;
+
+use image::imageops::{self, colorops::ColorMap};
+use image::{GrayImage, Rgb, RgbImage};
+
+use crate::Scan;
+
+/// Black, white, red, the default palette of `monochrome_image!`
+pub const BWR_PALETTE: [u32; 3] = [0x000000, 0xFFFFFF, 0xFF0000];
+
+/// Black, white, red, yellow, the palette of `quadcolor_image!`
+pub const BWYR_PALETTE: [u32; 4] = [0x000000, 0xFFFFFF, 0xFF0000, 0xFFFF00];
+
+/// Standard palette of 7-color ACeP panels, in controller index order:
+/// black, white, green, blue, red, yellow, orange
+pub const ACEP_PALETTE: [u32; 7] = [
+    0x000000, 0xFFFFFF, 0x00FF00, 0x0000FF, 0xFF0000, 0xFFFF00, 0xFF8000,
+];
+
+/// Color distance used when mapping pixels to a palette entry
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColorMetric {
+    /// Manhattan distance
+    L1,
+    /// (squared) Euclidean distance
+    #[default]
+    L2,
+    /// (squared) CIE76 ΔE, Euclidean distance in CIELAB, closer to perceived difference
+    Lab,
+}
+
+impl FromStr for ColorMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "l1" => Ok(ColorMetric::L1),
+            "l2" => Ok(ColorMetric::L2),
+            "lab" => Ok(ColorMetric::Lab),
+            _ => Err("expected `l1`, `l2` or `lab`".to_string()),
+        }
+    }
+}
+
+impl ColorMetric {
+    /// Distance of `c` to the `0xRRGGBB` color `p`, only comparable to other distances of
+    /// the same metric
+    pub fn distance(&self, c: &Rgb<u8>, p: u32) -> i32 {
+        let dr = c.0[0] as i32 - (p >> 16) as i32;
+        let dg = c.0[1] as i32 - ((p >> 8) & 0xFF) as i32;
+        let db = c.0[2] as i32 - (p & 0xFF) as i32;
+        match self {
+            ColorMetric::L1 => dr.abs() + dg.abs() + db.abs(),
+            ColorMetric::L2 => dr.pow(2) + dg.pow(2) + db.pow(2),
+            ColorMetric::Lab => {
+                let (c, p) = (
+                    to_lab(c.0),
+                    to_lab([(p >> 16) as u8, (p >> 8) as u8, p as u8]),
+                );
+                let d: f32 = (0..3).map(|i| (c[i] - p[i]).powi(2)).sum();
+                // ΔE is a few hundred at most, keep two decimals
+                (d * 100.0) as i32
+            }
+        }
+    }
+
+    /// Index of the palette entry nearest to `c`
+    pub fn nearest(&self, palette: &[u32], c: &Rgb<u8>) -> usize {
+        let mut min = 0;
+        let mut min_dist = 0x7FFF_FFFF;
+        for (i, p) in palette.iter().enumerate() {
+            let dist = self.distance(c, *p);
+            if dist < min_dist {
+                min_dist = dist;
+                min = i;
+            }
+        }
+        min
+    }
+}
+
+/// Convert an sRGB color to CIELAB, D65 white point
+fn to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|c| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Dithering algorithm of palette images
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Dither {
+    /// Floyd-Steinberg error diffusion
+    #[default]
+    Floyd,
+    /// Floyd-Steinberg error diffusion alternating the direction of rows, without the
+    /// directional artifacts on gradients
+    FloydSerpentine,
+    /// Atkinson error diffusion, spreads only 3/4 of the error for more contrast
+    Atkinson,
+    /// Bayer threshold matrix, regular patterns and clean flat regions
+    Ordered,
+    /// nearest palette color
+    None,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "floyd" => Ok(Dither::Floyd),
+            "floyd-serpentine" | "floyd_serpentine" => Ok(Dither::FloydSerpentine),
+            "atkinson" => Ok(Dither::Atkinson),
+            "ordered" | "bayer" => Ok(Dither::Ordered),
+            "none" => Ok(Dither::None),
+            _ => Err(
+                "expected `floyd`, `floyd-serpentine`, `atkinson`, `ordered`, `bayer` or `none`"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// Color map over an arbitrary palette of `0xRRGGBB` colors
+#[derive(Debug, Clone)]
+pub struct PaletteMap {
+    pub palette: Vec<u32>,
+    pub metric: ColorMetric,
+}
+
+impl PaletteMap {
+    /// Index of the palette entry nearest to `c`
+    pub fn map_palette(&self, c: &Rgb<u8>) -> u8 {
+        self.metric.nearest(&self.palette, c) as u8
+    }
+}
+
+impl ColorMap for PaletteMap {
+    type Color = Rgb<u8>;
+
+    fn index_of(&self, color: &Self::Color) -> usize {
+        self.metric.nearest(&self.palette, color)
+    }
+    fn map_color(&self, color: &mut Self::Color) {
+        let p = self.palette[self.index_of(color)];
+        *color = Rgb([(p >> 16) as u8, (p >> 8) as u8, p as u8]);
+    }
+}
+
+/// Dither `im` to the colors of `map` in place.
+///
+/// `bayer_size` is the size of the matrix of `Dither::Ordered`, 2, 4 or 8. Pixels white in
+/// `mask`, which must have the size of `im`, are mapped to the nearest color, and neither
+/// take nor spread any error.
+pub fn dither(
+    im: &mut RgbImage,
+    map: &PaletteMap,
+    dither: Dither,
+    bayer_size: u32,
+    mask: Option<&GrayImage>,
+) {
+    if dither == Dither::Floyd && mask.is_none() {
+        imageops::colorops::dither(im, map);
+        return;
+    }
+
+    // neighbors an error is spread to, (dx, dy, weight), and the sum the weights are out of
+    let (neighbors, total): (&[(i32, i32, i32)], i32) = match dither {
+        // 6/8 of the error is spread, the rest is dropped, for higher contrast
+        Dither::Atkinson => (
+            &[
+                (1, 0, 1),
+                (2, 0, 1),
+                (-1, 1, 1),
+                (0, 1, 1),
+                (1, 1, 1),
+                (0, 2, 1),
+            ],
+            8,
+        ),
+        _ => (&[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)], 16),
+    };
+
+    let (w, h) = im.dimensions();
+    // accumulated error per pixel and channel
+    let mut errors = vec![[0i32; 3]; (w * h) as usize];
+    for y in 0..h {
+        // odd rows of serpentine dithering run right to left, with the neighbors mirrored
+        let reverse = dither == Dither::FloydSerpentine && y % 2 == 1;
+        let dir = if reverse { -1 } else { 1 };
+        for i in 0..w {
+            let x = if reverse { w - 1 - i } else { i };
+            let px = im.get_pixel_mut(x, y);
+            let masked = mask.is_some_and(|mask| mask.get_pixel(x, y).0[0] >= 0x80);
+            if masked || dither == Dither::None {
+                map.map_color(px);
+                continue;
+            }
+            if dither == Dither::Ordered {
+                ordered_dither(px, x, y, bayer_size);
+                map.map_color(px);
+                continue;
+            }
+            let err = errors[(y * w + x) as usize];
+            let old = Rgb([0, 1, 2].map(|i| (px.0[i] as i32 + err[i]).clamp(0, 255) as u8));
+            *px = old;
+            map.map_color(px);
+            let diff = [0, 1, 2].map(|i| old.0[i] as i32 - px.0[i] as i32);
+
+            for &(dx, dy, weight) in neighbors {
+                let (nx, ny) = (x as i32 + dx * dir, y as i32 + dy);
+                if (0..w as i32).contains(&nx) && ny < h as i32 {
+                    let e = &mut errors[(ny as u32 * w + nx as u32) as usize];
+                    for i in 0..3 {
+                        e[i] += diff[i] * weight / total;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Offset a pixel by the threshold of a `size` x `size` Bayer matrix at its position, before
+/// mapping it to the nearest palette color.
+///
+/// The offsets span the full channel range, so two-color palettes get every gray level.
+fn ordered_dither(px: &mut Rgb<u8>, x: u32, y: u32, size: u32) {
+    // each bit of the position picks a cell of the 2x2 matrix [[0, 2], [3, 1]], the lowest
+    // bit the most significant one
+    const BAYER2: [[i32; 2]; 2] = [[0, 2], [3, 1]];
+    let bits = size.trailing_zeros();
+    let threshold = (0..bits).fold(0, |n, bit| {
+        let cell = BAYER2[(y >> bit & 1) as usize][(x >> bit & 1) as usize];
+        n + (cell << (2 * (bits - 1 - bit)))
+    });
+    // symmetric around the middle of the range, e.g. -126..=126 for 8x8
+    let offset = (threshold * 2 + 1) * 128 / (size * size) as i32 - 128;
+    for c in px.0.iter_mut() {
+        *c = (*c as i32 + offset).clamp(0, 255) as u8;
+    }
+}
+
+/// Pack the pixels of a palette-mapped image that map to palette index `channel` into a
+/// 1-bit plane in `scan` order.
+///
+/// Pixels flagged in `keyed`, one flag per pixel row by row, are left out. With `invert`,
+/// the bits of all other pixels are set instead.
+pub fn pack_monochrome(
+    im: &RgbImage,
+    map: &PaletteMap,
+    channel: u8,
+    keyed: Option<&[bool]>,
+    invert: bool,
+    scan: Scan,
+) -> Vec<u8> {
+    let (w, h) = im.dimensions();
+    scan.pack(w, h, |x, y| {
+        let is_key = keyed.is_some_and(|keyed| keyed[(y * w + x) as usize]);
+        (map.map_palette(im.get_pixel(x, y)) == channel && !is_key) != invert
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(palette: &[u32], metric: ColorMetric) -> PaletteMap {
+        PaletteMap {
+            palette: palette.to_vec(),
+            metric,
+        }
+    }
+
+    #[test]
+    fn metric_distance() {
+        let c = Rgb([10, 20, 30]);
+        assert_eq!(ColorMetric::L1.distance(&c, 0x000000), 60);
+        assert_eq!(ColorMetric::L2.distance(&c, 0x000000), 1400);
+        assert_eq!(ColorMetric::Lab.distance(&c, 0x0A141E), 0);
+        assert!(ColorMetric::Lab.distance(&c, 0xFFFFFF) > ColorMetric::Lab.distance(&c, 0x000000));
+    }
+
+    #[test]
+    fn parse_metric_names() {
+        assert_eq!("lab".parse(), Ok(ColorMetric::Lab));
+        assert!("l3".parse::<ColorMetric>().is_err());
+    }
+
+    #[test]
+    fn metric_nearest() {
+        assert_eq!(
+            ColorMetric::L2.nearest(&BWR_PALETTE, &Rgb([200, 30, 30])),
+            2
+        );
+        assert_eq!(
+            ColorMetric::L2.nearest(&BWR_PALETTE, &Rgb([200, 200, 200])),
+            1
+        );
+        // from black, L1 prefers one channel off by a lot, L2 all three off by a little
+        let palette = [0x780000, 0x323232];
+        let black = Rgb([0, 0, 0]);
+        assert_eq!(ColorMetric::L1.nearest(&palette, &black), 0);
+        assert_eq!(ColorMetric::L2.nearest(&palette, &black), 1);
+        // a dark blue is close to black in sRGB, but far in lightness and hue
+        let palette = [0x00003C, 0x282828];
+        assert_eq!(ColorMetric::L2.nearest(&palette, &black), 0);
+        assert_eq!(ColorMetric::Lab.nearest(&palette, &black), 1);
+    }
+
+    #[test]
+    fn dither_mask() {
+        let map = map(&[0x000000, 0xFFFFFF], ColorMetric::L2);
+        let gray = RgbImage::from_pixel(4, 1, Rgb([100; 3]));
+        let mut im = gray.clone();
+        dither(&mut im, &map, Dither::Floyd, 8, None);
+        assert!(im.pixels().any(|px| px.0 == [255; 3]));
+        // masked pixels are mapped to the nearest color, without spreading the error
+        let mut im = gray;
+        let mask = GrayImage::from_pixel(4, 1, image::Luma([0xFF]));
+        dither(&mut im, &map, Dither::Floyd, 8, Some(&mask));
+        assert!(im.pixels().all(|px| px.0 == [0; 3]));
+    }
+
+    #[test]
+    fn parse_dither_names() {
+        assert_eq!("floyd".parse(), Ok(Dither::Floyd));
+        assert_eq!("atkinson".parse(), Ok(Dither::Atkinson));
+        assert_eq!("ordered".parse(), Ok(Dither::Ordered));
+        assert_eq!("bayer".parse(), Ok(Dither::Ordered));
+        assert_eq!("none".parse(), Ok(Dither::None));
+        assert_eq!("floyd-serpentine".parse(), Ok(Dither::FloydSerpentine));
+        assert!("random".parse::<Dither>().is_err());
+    }
+
+    #[test]
+    fn dither_to_palette_colors() {
+        let gradient = RgbImage::from_fn(16, 8, |x, _| Rgb([(x * 16) as u8; 3]));
+        let map = map(&[0x000000, 0xFFFFFF], ColorMetric::L2);
+        for algorithm in [
+            Dither::Floyd,
+            Dither::FloydSerpentine,
+            Dither::Atkinson,
+            Dither::Ordered,
+            Dither::None,
+        ] {
+            let mut im = gradient.clone();
+            dither(&mut im, &map, algorithm, 4, None);
+            assert!(im.pixels().all(|px| px.0 == [0; 3] || px.0 == [255; 3]));
+        }
+    }
+
+    #[test]
+    fn bwyr_yellowish() {
+        let yellowish = Rgb([230, 210, 40]);
+        for metric in [ColorMetric::L1, ColorMetric::L2, ColorMetric::Lab] {
+            let map = map(&BWYR_PALETTE, metric);
+            assert_eq!(map.index_of(&yellowish), 3);
+            assert_eq!(map.map_palette(&yellowish), 3);
+            let mut c = yellowish;
+            map.map_color(&mut c);
+            assert_eq!(c, Rgb([0xFF, 0xFF, 0x00]));
+        }
+    }
+
+    #[test]
+    fn pack_channels() {
+        let im = RgbImage::from_fn(10, 1, |x, _| match x % 3 {
+            0 => Rgb([0, 0, 0]),
+            1 => Rgb([255, 255, 255]),
+            _ => Rgb([255, 0, 0]),
+        });
+        let map = map(&BWR_PALETTE, ColorMetric::L2);
+        assert_eq!(
+            pack_monochrome(&im, &map, 0, None, false, Scan::RowMsb),
+            [0x92, 0x40]
+        );
+        assert_eq!(
+            pack_monochrome(&im, &map, 2, None, false, Scan::RowMsb),
+            [0x24, 0x80]
+        );
+        // the padding bits of a row stay clear in inverted planes
+        assert_eq!(
+            pack_monochrome(&im, &map, 2, None, true, Scan::RowMsb),
+            [0xDB, 0x40]
+        );
+        let keyed: Vec<bool> = (0..10).map(|x| x == 0).collect();
+        assert_eq!(
+            pack_monochrome(&im, &map, 0, Some(&keyed), false, Scan::RowMsb),
+            [0x12, 0x40]
+        );
+    }
+
+    #[test]
+    fn atkinson_differs_from_floyd() {
+        let map = map(&[0x000000, 0xFFFFFF], ColorMetric::L2);
+        let dithered = |im: &RgbImage, algorithm| {
+            let mut im = im.clone();
+            dither(&mut im, &map, algorithm, 4, None);
+            im
+        };
+        let gradient = RgbImage::from_fn(32, 8, |x, _| Rgb([(x * 8) as u8; 3]));
+        assert_ne!(
+            dithered(&gradient, Dither::Atkinson),
+            dithered(&gradient, Dither::Floyd)
+        );
+        // Atkinson spreads only 3/4 of the error, light grays lose their darkest dots
+        let light = RgbImage::from_pixel(16, 16, Rgb([240; 3]));
+        let black = |im: RgbImage| im.pixels().filter(|px| px.0 == [0; 3]).count();
+        assert!(black(dithered(&light, Dither::Atkinson)) < black(dithered(&light, Dither::Floyd)));
+    }
+
+    #[test]
+    fn dither_mid_gray() {
+        let map = map(&[0x000000, 0xFFFFFF], ColorMetric::L2);
+        let mut im = RgbImage::from_pixel(8, 8, Rgb([128; 3]));
+        dither(&mut im, &map, Dither::Ordered, 2, None);
+        let white = im.pixels().filter(|px| px.0[0] == 255).count();
+        assert_eq!(white, 32);
+        // a 2x2 matrix repeats every other pixel
+        assert_eq!(im.get_pixel(0, 0), im.get_pixel(2, 2));
+
+        let mut im = RgbImage::from_pixel(8, 8, Rgb([128; 3]));
+        dither(&mut im, &map, Dither::None, 8, None);
+        assert!(im.pixels().all(|px| px.0 == [255; 3]));
+    }
+
+    #[test]
+    fn serpentine_reverses_odd_rows() {
+        let map = map(&[0x000000, 0xFFFFFF], ColorMetric::L2);
+        let gray = RgbImage::from_pixel(9, 2, Rgb([100; 3]));
+        let (mut floyd, mut serpentine) = (gray.clone(), gray);
+        // a mask pixel keeps the plain Floyd-Steinberg on this crate's diffusion
+        let mask = GrayImage::new(9, 2);
+        dither(&mut floyd, &map, Dither::Floyd, 8, Some(&mask));
+        dither(&mut serpentine, &map, Dither::FloydSerpentine, 8, None);
+        let row = |im: &RgbImage, y| (0..9).map(|x| im.get_pixel(x, y).0[0]).collect::<Vec<_>>();
+        // the first row is scanned left to right by both
+        assert_eq!(row(&floyd, 0), row(&serpentine, 0));
+        // the second row starts where the error of the first ended up, at the right
+        assert_ne!(row(&floyd, 1), row(&serpentine, 1));
+    }
+}
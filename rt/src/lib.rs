@@ -0,0 +1,89 @@
+//! The runtime side of the `text-image` macros, for firmware without `std` or an allocator.
+//!
+//! Data emitted with `compress = "packbits"` is PackBits-compressed. Each packet starts with
+//! a header byte `n`: `0..=127` is followed by `n + 1` literal bytes, `129..=255`
+//! (`-127..=-1` as `i8`) by one byte repeated `1 - n as i8` times, 2 to 128. 128 is a no-op.
+//!
+//! ```
+//! // 4 zeros, the literals 0xAA and 0x55, 3 times 0xFF
+//! let packed = [0xFD, 0x00, 0x01, 0xAA, 0x55, 0xFE, 0xFF];
+//! let mut buf = [0; 9];
+//! assert_eq!(text_image_rt::decompress(&packed, &mut buf), 9);
+//! assert_eq!(buf, [0, 0, 0, 0, 0xAA, 0x55, 0xFF, 0xFF, 0xFF]);
+//! ```
+
+#![no_std]
+
+/// Decompress PackBits data from `src` into `dst`, returning the number of bytes written.
+///
+/// Doesn't allocate or panic: output that doesn't fit `dst` and a packet cut short at the end
+/// of `src` are dropped.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> usize {
+    let (mut i, mut n) = (0, 0);
+    while i < src.len() && n < dst.len() {
+        let header = src[i];
+        i += 1;
+        match header {
+            0..=127 => {
+                let len = (header as usize + 1).min(src.len() - i).min(dst.len() - n);
+                dst[n..n + len].copy_from_slice(&src[i..i + len]);
+                i += header as usize + 1;
+                n += len;
+            }
+            128 => {}
+            _ => {
+                let Some(&b) = src.get(i) else {
+                    break;
+                };
+                i += 1;
+                let len = (257 - header as usize).min(dst.len() - n);
+                dst[n..n + len].fill(b);
+                n += len;
+            }
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets() {
+        let mut buf = [0; 8];
+        // a no-op, then a run of 2 and a literal of 1
+        assert_eq!(decompress(&[0x80, 0xFF, 7, 0x00, 9], &mut buf), 3);
+        assert_eq!(buf[..3], [7, 7, 9]);
+        // the longest run
+        let mut buf = [0; 130];
+        assert_eq!(decompress(&[0x81, 5], &mut buf), 128);
+        assert!(buf[..128].iter().all(|&b| b == 5));
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(decompress(&[], &mut [0; 4]), 0);
+        assert_eq!(decompress(&[0xFE, 1], &mut []), 0);
+    }
+
+    #[test]
+    fn short_dst() {
+        let mut buf = [0; 3];
+        assert_eq!(decompress(&[0xFD, 1], &mut buf), 3);
+        assert_eq!(buf, [1, 1, 1]);
+        assert_eq!(decompress(&[4, 1, 2, 3, 4, 5], &mut buf), 3);
+        assert_eq!(buf, [1, 2, 3]);
+    }
+
+    #[test]
+    fn truncated_src() {
+        let mut buf = [0; 8];
+        // a literal missing its last bytes keeps those present
+        assert_eq!(decompress(&[3, 1, 2], &mut buf), 2);
+        assert_eq!(buf[..2], [1, 2]);
+        // a run missing its byte is dropped
+        assert_eq!(decompress(&[0, 9, 0xFE], &mut buf), 1);
+        assert_eq!(buf[0], 9);
+    }
+}
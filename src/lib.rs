@@ -1,40 +1,390 @@
-#![feature(iter_array_chunks)]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use ab_glyph::{Font, FontRef, PxScale, ScaleFont};
-use image::{GenericImageView, GrayImage, Luma, Rgb};
-use imageproc::drawing::{draw_text_mut, text_size};
+use image::imageops::FilterType;
+use image::{GenericImageView, Rgb};
 use proc_macro::TokenStream;
 use quote::quote;
 use syn::parse::{Parse, ParseStream, Result};
-use syn::{parse_macro_input, Ident, Lit, LitByteStr, Token};
+use syn::{parse_macro_input, Ident, Lit, LitByteStr, LitInt, LitStr, Token};
+use text_image_core::{
+    compress, dither, pack_gray, pack_monochrome, ColorMetric, Dither, PaletteMap, Scan, TextImage,
+    TextImageBuilder, TextImageError, TextImageOptions, ACEP_PALETTE, BWR_PALETTE, BWYR_PALETTE,
+    MIN_FIT_SIZE,
+};
+
+/// File of font aliases for `font = @alias`, relative to the crate's `Cargo.toml`
+///
+/// A `register_font!` macro can't take its place: expansions share no state the compiler
+/// guarantees, a registry in a static is only seen by calls expanded after it in the same
+/// process, which neither the expansion order nor incremental builds and rust-analyzer
+/// promise. A file read by every call gives the same aliases to each of them.
+const FONT_ALIASES_FILE: &str = "text-image-fonts.txt";
+
+/// Print a message during expansion, only with the `debug` feature
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "debug") {
+            println!($($arg)*);
+        }
+    };
+}
 
 #[derive(Debug)]
-struct TextImageOptions {
-    text: String,
-    font: String,
-    font_size: f32,
-    inverse: bool,
-    line_spacing: i32,
-    // 2, 4, or 8
+struct TextImageArgs {
+    opts: TextImageOptions,
+    output: OutputOptions,
+    /// span of `font` or `font_data`, for errors about the font
+    font_span: Option<proc_macro2::Span>,
+    /// span of each of `fallback_fonts`
+    fallback_spans: Vec<(PathBuf, proc_macro2::Span)>,
+    /// expand to `(w, content_w, h, raw)`, the flag itself for its span
+    content_width: Option<Ident>,
+}
+
+/// Options handled by `OutputOptions`, accepted by every macro
+const OUTPUT_OPTIONS: &[&str] = &[
+    "pad_to",
+    "const_fn",
+    "as_image_raw",
+    "with_stride",
+    "out_dir",
+    "as_array",
+    "compress",
+];
+
+/// Gray depth options, accepted by every macro
+const GRAY_OPTIONS: &[&str] = &["gray_depth", "Gray2", "Gray4", "Gray8"];
+
+/// Options of `text_image!` and `text_image_size!`, besides `OUTPUT_OPTIONS` and `GRAY_OPTIONS`
+const TEXT_IMAGE_OPTIONS: &[&str] = &[
+    "text",
+    "text_file",
+    "font",
+    "font_data",
+    "fallback_fonts",
+    "font_size",
+    "line_spacing",
+    "gamma",
+    "coverage_gamma",
+    "padding",
+    "max_width",
+    "cell_height",
+    "canvas_height",
+    "blank_line_height",
+    "valign",
+    "bidi",
+    "rtl",
+    "normalize",
+    "rotate",
+    "content_width",
+    "align",
+    "align_mode",
+    "scan",
+    "dither_edges",
+    "bold",
+    "dither",
+    "rounding",
+    "threshold",
+    "italic",
+    "underline",
+    "strikethrough",
+    "ligatures",
+    "require_chars",
+    "require_chars_file",
+    "axes",
+    "offsets",
+    "max_lines",
+    "fit_width",
+    "fit_height",
+    "ellipsis",
+    "tab_width",
+    "letter_spacing",
+    "origin_x",
+    "origin_y",
+    "max_ascent",
+    "cap_metrics",
+    "leader",
+    "inverse",
+    "background",
+    "foreground",
+];
+
+/// Options of the image macros, besides `OUTPUT_OPTIONS` and `GRAY_OPTIONS`
+const IMAGE_OPTIONS: &[&str] = &[
+    "channel",
+    "gamma",
+    "rounding",
+    "index_buffer",
+    "interleave",
+    "invert",
+    "planes",
+    "all_planes",
+    "color_key",
+    "background",
+    "crop",
+    "resize",
+    "filter",
+    "rotate",
+    "flip_h",
+    "flip_v",
+    "key_tolerance",
+    "metric",
+    "dither",
+    "bayer_size",
+    "scan",
+    "palette",
+    "fallback",
+    "save_indexed_png",
+    "no_dither_mask",
+];
+
+/// Options of `test_pattern!`, besides `OUTPUT_OPTIONS` and `GRAY_OPTIONS`
+const TEST_PATTERN_OPTIONS: &[&str] = &["pattern", "width", "height", "stripe", "scan"];
+
+/// Options of `seven_segment!`, besides `OUTPUT_OPTIONS` and `GRAY_OPTIONS`
+const SEVEN_SEGMENT_OPTIONS: &[&str] = &["height", "thickness", "slant", "scan"];
+
+/// Check that `name` is one of `options`, with an error listing them all otherwise
+fn check_option(name: &Ident, options: &[&[&str]]) -> Result<()> {
+    let options: Vec<&str> = options
+        .iter()
+        .flat_map(|options| options.iter().copied())
+        .collect();
+    if options.contains(&&*name.to_string()) {
+        return Ok(());
+    }
+    let names: Vec<String> = options
+        .iter()
+        .map(|option| format!("`{}`", option))
+        .collect();
+    Err(syn::Error::new_spanned(
+        name,
+        format!(
+            "unknown option `{}`, expected one of {}",
+            name,
+            names.join(", ")
+        ),
+    ))
+}
+
+/// Parse a keyword option value, accepting both `opt = value` and `opt = "value"`.
+fn parse_keyword(input: ParseStream) -> Result<(String, proc_macro2::Span)> {
+    if input.peek(Ident) {
+        let ident: Ident = input.parse()?;
+        Ok((ident.to_string(), ident.span()))
+    } else {
+        let lit: syn::LitStr = input.parse()?;
+        Ok((lit.value(), lit.span()))
+    }
+}
+
+/// Parse a keyword option value into a type implementing `FromStr`.
+fn parse_keyword_as<T: FromStr<Err = String>>(input: ParseStream) -> Result<T> {
+    let (value, span) = parse_keyword(input)?;
+    value.parse().map_err(|err| syn::Error::new(span, err))
+}
+
+/// Parse a parenthesized list of unsigned integers, e.g. `(300, 240)`
+fn parse_u32_tuple(input: ParseStream) -> Result<Vec<u32>> {
+    let content;
+    syn::parenthesized!(content in input);
+    let values = content.parse_terminated(LitInt::parse, Token![,])?;
+    values.iter().map(|value| value.base10_parse()).collect()
+}
+
+/// Parse a float or integer literal as `f32`, e.g. `16.0` or `16`, naming `option` in the
+/// error
+fn parse_f32(input: ParseStream, option: &str) -> Result<f32> {
+    match input.parse()? {
+        Lit::Float(lit) => lit.base10_parse(),
+        Lit::Int(lit) => lit.base10_parse::<u32>().map(|n| n as f32),
+        lit => Err(syn::Error::new_spanned(
+            lit,
+            format!("`{}` expects a number, e.g. `{} = 16.0`", option, option),
+        )),
+    }
+}
+
+/// Parse an integer literal expression, possibly negative, e.g. `-2`
+fn parse_int_expr(expr: &syn::Expr) -> Result<i32> {
+    match expr {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: Lit::Int(lit), ..
+        }) => lit.base10_parse(),
+        syn::Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        }) => parse_int_expr(expr).map(|n| -n),
+        _ => Err(syn::Error::new_spanned(expr, "expected a integer literal")),
+    }
+}
+
+/// Parse a `gray_depth` value, `4`, or `env!("DISPLAY_DEPTH")` to read it from the
+/// environment of the build, e.g. set by a build script with `cargo:rustc-env`.
+fn parse_gray_depth(input: ParseStream) -> Result<u8> {
+    let (depth, span, value) = if input.peek(Ident) && input.peek2(Token![!]) {
+        let mac: syn::Macro = input.parse()?;
+        if !mac.path.is_ident("env") {
+            return Err(syn::Error::new_spanned(
+                mac.path,
+                "expected a integer literal or `env!(\"NAME\")`",
+            ));
+        }
+        let name: LitStr = mac.parse_body()?;
+        let Ok(value) = std::env::var(name.value()) else {
+            return Err(syn::Error::new_spanned(
+                &name,
+                format!("environment variable {} is not set", name.value()),
+            ));
+        };
+        (value.trim().parse().ok(), name.span(), value)
+    } else {
+        let lit: LitInt = input.parse()?;
+        (lit.base10_parse().ok(), lit.span(), lit.to_string())
+    };
+    match depth {
+        Some(depth @ (1 | 2 | 4 | 8)) => Ok(depth),
+        _ => Err(syn::Error::new(
+            span,
+            format!("invalid gray depth {}, expected 1, 2, 4 or 8", value),
+        )),
+    }
+}
+
+/// Resolve a relative path against the manifest directory of the crate being compiled.
+///
+/// The working directory of the compiler differs between cargo, IDEs and other build
+/// setups, so a relative path is taken relative to the crate first. If it doesn't exist
+/// there but relative to the working directory, it is kept as given, as in earlier
+/// versions. Otherwise the absolute path is returned, so errors name the path attempted.
+fn resolve_path(path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    let Some(dir) = std::env::var_os("CARGO_MANIFEST_DIR") else {
+        return path;
+    };
+    if path.is_absolute() {
+        return path;
+    }
+    let resolved = Path::new(&dir).join(&path);
+    if !resolved.exists() && path.exists() {
+        path
+    } else {
+        resolved
+    }
+}
+
+/// Font aliases of `FONT_ALIASES_FILE`, in file order.
+///
+/// Each line is `alias = path`, the path relative to the crate's `Cargo.toml` and optionally
+/// quoted. Empty lines and lines starting with `#` are skipped.
+fn font_aliases() -> std::result::Result<Vec<(String, PathBuf)>, String> {
+    let path = resolve_path(FONT_ALIASES_FILE);
+    let content = std::fs::read_to_string(&path)
+        .map_err(|err| format!("Can not read {}: {}", path.display(), err))?;
+    let mut aliases: Vec<(String, PathBuf)> = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((alias, font)) = line
+            .split_once('=')
+            .map(|(alias, font)| (alias.trim(), font.trim().trim_matches('"')))
+            .filter(|(alias, font)| syn::parse_str::<Ident>(alias).is_ok() && !font.is_empty())
+        else {
+            return Err(format!(
+                "{}:{}: expected `alias = path`",
+                path.display(),
+                i + 1
+            ));
+        };
+        if aliases.iter().any(|(name, _)| name == alias) {
+            return Err(format!(
+                "{}:{}: duplicate font alias `{}`",
+                path.display(),
+                i + 1,
+                alias
+            ));
+        }
+        aliases.push((alias.to_string(), resolve_path(font)));
+    }
+    Ok(aliases)
+}
+
+/// Path of the font listed under `alias` in `FONT_ALIASES_FILE`
+fn aliased_font(alias: &Ident) -> Result<PathBuf> {
+    let aliases = font_aliases().map_err(|err| syn::Error::new_spanned(alias, err))?;
+    if let Some((_, font)) = aliases.iter().find(|(name, _)| *alias == name) {
+        return Ok(font.clone());
+    }
+    let names: Vec<&str> = aliases.iter().map(|(name, _)| &**name).collect();
+    Err(syn::Error::new_spanned(
+        alias,
+        format!(
+            "unknown font alias `{}`, {} lists: {}",
+            alias,
+            FONT_ALIASES_FILE,
+            if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(", ")
+            }
+        ),
+    ))
+}
+
+/// Reject a `scan` order combined with output it doesn't apply to.
+///
+/// | `scan`            | 1-bit | Gray2/4/8 | `index_buffer` |
+/// |-------------------|-------|-----------|----------------|
+/// | `row_msb`         | yes   | yes       | yes            |
+/// | any other         | yes   | no        | no             |
+fn check_scan(
+    scan: Scan,
+    span: Option<proc_macro2::Span>,
     gray_depth: i32,
-    // gamma < 1.0, darker, gamma > 1.0, lighter
-    gamma: f32,
+    index_buffer: bool,
+) -> Result<()> {
+    let Some(span) = span.filter(|_| scan != Scan::RowMsb) else {
+        return Ok(());
+    };
+    if gray_depth != 1 {
+        return Err(syn::Error::new(
+            span,
+            format!(
+                "scan other than row_msb only applies to 1-bit output, not Gray{}",
+                gray_depth
+            ),
+        ));
+    }
+    if index_buffer {
+        return Err(syn::Error::new(
+            span,
+            "scan can not be combined with index_buffer, which emits one byte per pixel",
+        ));
+    }
+    Ok(())
 }
 
-impl Parse for TextImageOptions {
+impl Parse for TextImageArgs {
     fn parse(input: ParseStream) -> Result<Self> {
-        let mut opts = TextImageOptions {
-            text: "".to_string(),
-            font: "".to_string(),
-            font_size: 16.0,
-            inverse: false,
-            line_spacing: 0,
-            gray_depth: 1,
-            gamma: 1.0,
-        };
+        let mut opts = TextImageOptions::default();
+        let mut output = OutputOptions::default();
+        let mut content_width = None;
+        let mut scan_span = None;
+        let mut inverse_span = None;
+        let mut font_data_span = None;
+        let mut font_span = None;
+        let mut text_span = None;
+        let mut text_file_span = None;
+        let mut fallback_spans = vec![];
+        let mut color_span = None;
 
         loop {
             let name: Ident = input.parse()?;
+            check_option(&name, &[TEXT_IMAGE_OPTIONS, OUTPUT_OPTIONS, GRAY_OPTIONS])?;
 
             match &*name.to_string() {
                 "text" => {
@@ -48,33 +398,62 @@ impl Parse for TextImageOptions {
                     };
 
                     opts.text = text;
+                    text_span = Some(name.span());
+                }
+                "text_file" => {
+                    input.parse::<Token![=]>()?;
+                    let path: LitStr = input.parse()?;
+                    opts.text =
+                        std::fs::read_to_string(resolve_path(&path.value())).map_err(|err| {
+                            syn::Error::new_spanned(
+                                &path,
+                                format!("Can not read {}: {}", path.value(), err),
+                            )
+                        })?;
+                    text_file_span = Some(path.span());
                 }
                 "font" => {
                     input.parse::<Token![=]>()?;
-                    let font: Lit = input.parse()?;
-
-                    let font = if let Lit::Str(font) = &font {
-                        font.value()
+                    if input.peek(Token![@]) {
+                        // alias listed in `FONT_ALIASES_FILE`
+                        input.parse::<Token![@]>()?;
+                        let alias: Ident = input.parse()?;
+                        opts.font = aliased_font(&alias)?;
+                        font_span = Some(alias.span());
                     } else {
-                        return Err(syn::Error::new_spanned(font, "expected a string literal"));
-                    };
+                        let font: Lit = input.parse()?;
+                        font_span = Some(font.span());
+
+                        let font = if let Lit::Str(font) = &font {
+                            font.value()
+                        } else {
+                            return Err(syn::Error::new_spanned(font, "expected a string literal"));
+                        };
 
-                    opts.font = font;
+                        opts.font = resolve_path(&font);
+                    }
+                }
+                "font_data" => {
+                    input.parse::<Token![=]>()?;
+                    let data: LitByteStr = input.parse()?;
+                    opts.font_data = Some(data.value());
+                    font_data_span = Some(data.span());
+                    font_span = Some(data.span());
+                }
+                "fallback_fonts" => {
+                    input.parse::<Token![=]>()?;
+                    let content;
+                    syn::bracketed!(content in input);
+                    let fonts = content.parse_terminated(<LitStr as Parse>::parse, Token![,])?;
+                    for font in fonts {
+                        let path = resolve_path(&font.value());
+                        opts.fallback_fonts.push(path.clone());
+                        fallback_spans.push((path, font.span()));
+                    }
                 }
                 "font_size" => {
                     input.parse::<Token![=]>()?;
-                    let font_size: Lit = input.parse()?;
-
-                    let font_size = if let Lit::Float(font_size) = &font_size {
-                        font_size.base10_parse()?
-                    } else {
-                        return Err(syn::Error::new_spanned(
-                            font_size,
-                            "expected a float literal",
-                        ));
-                    };
-
-                    opts.font_size = font_size;
+                    opts.font_size = parse_f32(input, "font_size")?;
                 }
                 "line_spacing" => {
                     input.parse::<Token![=]>()?;
@@ -85,7 +464,7 @@ impl Parse for TextImageOptions {
                     } else {
                         return Err(syn::Error::new_spanned(
                             line_spacing,
-                            "expected a integer literal",
+                            "`line_spacing` expects a whole number of pixels, e.g. `line_spacing = 2`",
                         ));
                     };
 
@@ -105,8 +484,350 @@ impl Parse for TextImageOptions {
                     }
                     opts.gamma = gamma;
                 }
+                "coverage_gamma" => {
+                    input.parse::<Token![=]>()?;
+                    let coverage_gamma: Lit = input.parse()?;
+
+                    let coverage_gamma = if let Lit::Float(coverage_gamma) = &coverage_gamma {
+                        coverage_gamma.base10_parse()?
+                    } else {
+                        return Err(syn::Error::new_spanned(
+                            coverage_gamma,
+                            "expected a float literal",
+                        ));
+                    };
+                    if coverage_gamma <= 0.0 {
+                        return Err(syn::Error::new_spanned(
+                            name,
+                            "coverage_gamma must be positive",
+                        ));
+                    }
+                    opts.coverage_gamma = coverage_gamma;
+                }
+                "padding" => {
+                    input.parse::<Token![=]>()?;
+                    let parse_pad = |input: ParseStream| -> Result<u32> {
+                        input.parse::<LitInt>()?.base10_parse()
+                    };
+                    if input.peek(syn::token::Paren) {
+                        // (top, right, bottom, left)
+                        let content;
+                        syn::parenthesized!(content in input);
+                        let pads = content.parse_terminated(parse_pad, Token![,])?;
+                        let Ok(padding) =
+                            <[u32; 4]>::try_from(pads.into_iter().collect::<Vec<_>>())
+                        else {
+                            return Err(syn::Error::new_spanned(
+                                name,
+                                "expected `padding = n` or `padding = (top, right, bottom, left)`",
+                            ));
+                        };
+                        opts.padding = padding;
+                    } else {
+                        opts.padding = [parse_pad(input)?; 4];
+                    }
+                }
+                "max_width" => {
+                    input.parse::<Token![=]>()?;
+                    let max_width: LitInt = input.parse()?;
+                    let value: u32 = max_width.base10_parse()?;
+                    if value == 0 {
+                        return Err(syn::Error::new_spanned(
+                            max_width,
+                            "max_width must be positive",
+                        ));
+                    }
+                    opts.max_width = Some(value);
+                }
+                "cell_height" => {
+                    input.parse::<Token![=]>()?;
+                    let cell_height: Lit = input.parse()?;
+
+                    let cell_height = if let Lit::Int(cell_height) = &cell_height {
+                        cell_height.base10_parse()?
+                    } else {
+                        return Err(syn::Error::new_spanned(
+                            cell_height,
+                            "expected a integer literal",
+                        ));
+                    };
+                    if cell_height <= 0 {
+                        return Err(syn::Error::new_spanned(
+                            name,
+                            "cell_height must be positive",
+                        ));
+                    }
+
+                    opts.cell_height = Some(cell_height);
+                }
+                "canvas_height" => {
+                    input.parse::<Token![=]>()?;
+                    let canvas_height: LitInt = input.parse()?;
+                    let value: u32 = canvas_height.base10_parse()?;
+                    if value == 0 {
+                        return Err(syn::Error::new_spanned(
+                            canvas_height,
+                            "canvas_height must be positive",
+                        ));
+                    }
+                    opts.canvas_height = Some(value);
+                }
+                "blank_line_height" => {
+                    input.parse::<Token![=]>()?;
+                    let blank_line_height: LitInt = input.parse()?;
+                    let value: i32 = blank_line_height.base10_parse()?;
+                    if value < 0 {
+                        return Err(syn::Error::new_spanned(
+                            blank_line_height,
+                            "blank_line_height must not be negative",
+                        ));
+                    }
+                    opts.blank_line_height = Some(value);
+                }
+                "valign" => {
+                    input.parse::<Token![=]>()?;
+                    opts.valign = parse_keyword_as(input)?;
+                }
+                "bidi" => {
+                    if cfg!(not(feature = "bidi")) {
+                        return Err(syn::Error::new_spanned(
+                            name,
+                            "option `bidi` requires the `bidi` feature of text-image",
+                        ));
+                    }
+                    opts.bidi = true;
+                }
+                "rtl" => {
+                    opts.rtl = true;
+                }
+                "normalize" => {
+                    if cfg!(not(feature = "normalize")) {
+                        return Err(syn::Error::new_spanned(
+                            name,
+                            "option `normalize` requires the `normalize` feature of text-image",
+                        ));
+                    }
+                    opts.normalize = true;
+                }
+                "rotate" => {
+                    input.parse::<Token![=]>()?;
+                    let rotate: LitInt = input.parse()?;
+                    opts.rotate = rotate.base10_parse()?;
+                    if ![0, 90, 180, 270].contains(&opts.rotate) {
+                        return Err(syn::Error::new_spanned(
+                            rotate,
+                            "expected a rotation of 0, 90, 180 or 270",
+                        ));
+                    }
+                }
+                option if OUTPUT_OPTIONS.contains(&option) => {
+                    output.parse_option(&name, input)?;
+                }
+                "content_width" => {
+                    content_width = Some(name.clone());
+                }
+                "align" => {
+                    input.parse::<Token![=]>()?;
+                    opts.align = parse_keyword_as(input)?;
+                }
+                "align_mode" => {
+                    input.parse::<Token![=]>()?;
+                    opts.align_mode = parse_keyword_as(input)?;
+                }
+                "scan" => {
+                    input.parse::<Token![=]>()?;
+                    opts.scan = parse_keyword_as(input)?;
+                    scan_span = Some(name.span());
+                }
+                "dither_edges" => {
+                    opts.dither_edges = true;
+                }
+                "bold" => {
+                    opts.bold = true;
+                }
+                "dither" => {
+                    opts.dither = true;
+                }
+                "rounding" => {
+                    opts.rounding = true;
+                }
+                "threshold" => {
+                    input.parse::<Token![=]>()?;
+                    let threshold: LitInt = input.parse()?;
+                    opts.threshold = threshold.base10_parse()?;
+                }
+                "italic" => {
+                    opts.italic = true;
+                }
+                "underline" => {
+                    opts.underline = true;
+                }
+                "strikethrough" => {
+                    opts.strikethrough = true;
+                }
+                "ligatures" => {
+                    input.parse::<Token![=]>()?;
+                    let content;
+                    syn::bracketed!(content in input);
+                    while !content.is_empty() {
+                        let pair;
+                        syn::parenthesized!(pair in content);
+                        let seq: LitStr = pair.parse()?;
+                        pair.parse::<Token![,]>()?;
+                        let codepoint: LitInt = pair.parse()?;
+                        let Some(c) = char::from_u32(codepoint.base10_parse()?) else {
+                            return Err(syn::Error::new_spanned(
+                                codepoint,
+                                "invalid unicode codepoint",
+                            ));
+                        };
+                        opts.ligatures.push((seq.value(), c));
+
+                        if content.is_empty() {
+                            break;
+                        }
+                        content.parse::<Token![,]>()?;
+                    }
+                }
+                "require_chars" => {
+                    input.parse::<Token![=]>()?;
+                    let chars: LitStr = input.parse()?;
+                    opts.require_chars.push_str(&chars.value());
+                }
+                "require_chars_file" => {
+                    input.parse::<Token![=]>()?;
+                    let path: LitStr = input.parse()?;
+                    let chars =
+                        std::fs::read_to_string(resolve_path(&path.value())).map_err(|err| {
+                            syn::Error::new_spanned(
+                                &path,
+                                format!("Can not read {}: {}", path.value(), err),
+                            )
+                        })?;
+                    opts.require_chars.push_str(&chars);
+                }
+                "axes" => {
+                    input.parse::<Token![=]>()?;
+                    let content;
+                    syn::bracketed!(content in input);
+                    while !content.is_empty() {
+                        let pair;
+                        syn::parenthesized!(pair in content);
+                        let tag: LitStr = pair.parse()?;
+                        pair.parse::<Token![,]>()?;
+                        let value: Lit = pair.parse()?;
+                        if tag.value().len() != 4 || !tag.value().is_ascii() {
+                            return Err(syn::Error::new_spanned(
+                                tag,
+                                "expected a 4 letter axis tag like \"wght\"",
+                            ));
+                        }
+                        let value = match &value {
+                            Lit::Float(value) => value.base10_parse()?,
+                            Lit::Int(value) => value.base10_parse()?,
+                            _ => {
+                                return Err(syn::Error::new_spanned(
+                                    value,
+                                    "expected a float literal",
+                                ))
+                            }
+                        };
+                        opts.axes.push((tag.value(), value));
+
+                        if content.is_empty() {
+                            break;
+                        }
+                        content.parse::<Token![,]>()?;
+                    }
+                }
+                "offsets" => {
+                    input.parse::<Token![=]>()?;
+                    let content;
+                    syn::bracketed!(content in input);
+                    let offsets = content.parse_terminated(syn::Expr::parse, Token![,])?;
+                    for offset in offsets {
+                        opts.offsets.push(parse_int_expr(&offset)?);
+                    }
+                }
+                "max_lines" => {
+                    input.parse::<Token![=]>()?;
+                    let max_lines: LitInt = input.parse()?;
+                    opts.max_lines = Some(max_lines.base10_parse()?);
+                }
+                "fit_width" => {
+                    input.parse::<Token![=]>()?;
+                    let fit_width: LitInt = input.parse()?;
+                    opts.fit_width = Some(fit_width.base10_parse()?);
+                }
+                "fit_height" => {
+                    input.parse::<Token![=]>()?;
+                    let fit_height: LitInt = input.parse()?;
+                    opts.fit_height = Some(fit_height.base10_parse()?);
+                }
+                "ellipsis" => {
+                    input.parse::<Token![=]>()?;
+                    let ellipsis: LitStr = input.parse()?;
+                    opts.ellipsis = ellipsis.value();
+                }
+                "tab_width" => {
+                    input.parse::<Token![=]>()?;
+                    let tab_width: LitInt = input.parse()?;
+                    opts.tab_width = tab_width.base10_parse()?;
+                }
+                "letter_spacing" => {
+                    input.parse::<Token![=]>()?;
+                    opts.letter_spacing = parse_int_expr(&input.parse()?)?;
+                }
+                "origin_x" | "origin_y" => {
+                    input.parse::<Token![=]>()?;
+                    let origin = parse_int_expr(&input.parse()?)?;
+                    if name == "origin_x" {
+                        opts.origin_x = origin;
+                    } else {
+                        opts.origin_y = origin;
+                    }
+                }
+                "max_ascent" => {
+                    input.parse::<Token![=]>()?;
+                    let max_ascent: Lit = input.parse()?;
+
+                    let max_ascent = if let Lit::Float(max_ascent) = &max_ascent {
+                        max_ascent.base10_parse()?
+                    } else {
+                        return Err(syn::Error::new_spanned(
+                            max_ascent,
+                            "expected a float literal",
+                        ));
+                    };
+
+                    opts.max_ascent = Some(max_ascent);
+                }
+                "cap_metrics" => {
+                    opts.cap_metrics = true;
+                }
+                "leader" => {
+                    input.parse::<Token![=]>()?;
+                    let leader: LitStr = input.parse()?;
+                    opts.leader = Some(leader.value());
+                }
                 "inverse" => {
                     opts.inverse = true;
+                    inverse_span = Some(name.span());
+                }
+                "background" | "foreground" => {
+                    input.parse::<Token![=]>()?;
+                    let luma: LitInt = input.parse()?;
+                    let luma = luma.base10_parse()?;
+                    if name == "background" {
+                        opts.background = Some(luma);
+                    } else {
+                        opts.foreground = Some(luma);
+                    }
+                    color_span = Some(name.span());
+                }
+                "gray_depth" => {
+                    input.parse::<Token![=]>()?;
+                    opts.gray_depth = parse_gray_depth(input)?;
                 }
                 "Gray2" => {
                     opts.gray_depth = 2;
@@ -117,12 +838,8 @@ impl Parse for TextImageOptions {
                 "Gray8" => {
                     opts.gray_depth = 8;
                 }
-                _ => {
-                    return Err(syn::Error::new_spanned(
-                        name,
-                        "expected `text`, `font`, `font_size` or `inverse`",
-                    ));
-                }
+                // names not in the option lists were rejected by `check_option`
+                _ => unreachable!(),
             }
 
             let _ = input.parse::<Token![,]>();
@@ -132,33 +849,207 @@ impl Parse for TextImageOptions {
         }
 
         // check required
-        if opts.text.is_empty() {
-            return Err(syn::Error::new_spanned(
-                "text",
-                "required option `text` is missing",
-            ));
+        match (text_span, text_file_span) {
+            (Some(_), Some(text_file_span)) => {
+                return Err(syn::Error::new(
+                    text_file_span,
+                    "`text` and `text_file` can not be combined, use one of them",
+                ));
+            }
+            (None, Some(text_file_span)) if opts.text.is_empty() => {
+                return Err(syn::Error::new(text_file_span, "`text_file` is empty"));
+            }
+            _ if opts.text.is_empty() => {
+                return Err(syn::Error::new_spanned(
+                    "text",
+                    "required option `text` or `text_file` is missing",
+                ));
+            }
+            _ => {}
         }
-        if opts.font.is_empty() {
-            return Err(syn::Error::new_spanned(
-                "font",
-                "required option `font` is missing",
+        match (opts.font.as_os_str().is_empty(), font_data_span) {
+            (true, None) => {
+                return Err(syn::Error::new_spanned(
+                    "font",
+                    "required option `font` or `font_data` is missing",
+                ));
+            }
+            (false, Some(font_data_span)) => {
+                return Err(syn::Error::new(
+                    font_data_span,
+                    "`font` and `font_data` can not be combined, use one of them",
+                ));
+            }
+            _ => {}
+        }
+
+        check_scan(opts.scan, scan_span, opts.gray_depth as i32, false)?;
+        if let Some(content_width) = &content_width {
+            let other = [&output.const_fn, &output.image_raw, &output.stride]
+                .into_iter()
+                .flatten()
+                .next();
+            if let Some(other) = other {
+                return Err(syn::Error::new_spanned(
+                    content_width,
+                    format!("`content_width` can not be combined with `{}`", other),
+                ));
+            }
+        }
+        if let (Some(_), Some(color_span)) = (inverse_span, color_span) {
+            return Err(syn::Error::new(
+                color_span,
+                "explicit colors can not be combined with `inverse`, which is a shorthand \
+                 for `background = 0xFF, foreground = 0x00`",
             ));
         }
 
-        Ok(opts)
+        Ok(TextImageArgs {
+            opts,
+            output,
+            font_span,
+            fallback_spans,
+            content_width,
+        })
     }
 }
 
 /// Generate a text image.
 ///
 /// Parameters:
-/// - `text`: text to render
-/// - `font`: font file path
-/// - `font_size`: font size
-/// - `line_spacing`: line spacing
+/// - `text`: text to render; escapes like `\n` and `\t` work as in any Rust string literal,
+///   so `"line1\nline2"` is two lines, and `\r\n` line breaks count as one. Raw strings
+///   `r"..."` keep backslashes as they are
+/// - `text_file`: read the text from a UTF-8 file instead, relative to the crate's
+///   `Cargo.toml`, e.g. strings maintained by translators; each line of the file is a line
+///   of the image
+/// - `font`: font file path, or `@alias` of a font listed in `text-image-fonts.txt`; relative
+///   paths are resolved against the crate's `CARGO_MANIFEST_DIR`, falling back to the
+///   working directory of the compiler
+/// - `font_data`: e.g. `b"\x00\x01\x00\x00..."`, the font file contents as a byte string,
+///   instead of `font`, e.g. a generated subset font; exactly one of them is required
+/// - `fallback_fonts`: e.g. `["NotoSansCJK.otf", "Symbols.ttf"]`, fonts tried in order for
+///   each char `font` has no glyph for; the line height grows to the tallest font used
+/// - `font_size`: font size in pixels, `16.0` or `16`
+/// - `line_spacing`: additional space between lines, in whole pixels
+/// - `tab_width`: tabs are expanded to spaces up to the next multiple of this many columns,
+///   default 4; ignored with `leader`, which takes the tab as separator
+/// - `letter_spacing`: extra pixels between glyphs, e.g. `letter_spacing = 3` for spaced-out
+///   titles; negative values tighten, but never move a glyph left of the previous one
 /// - `gamma`: gamma correction, default 1.0, < 1.0 darker, > 1.0 lighter
+/// - `coverage_gamma`: gamma applied to glyph coverage before blending and depth reduction,
+///   default 1.0; < 1.0 makes thin strokes of light fonts more solid, while solid areas
+///   stay unchanged, unlike `gamma` which applies to the whole image
+/// - `padding`: blank space around the text in pixels, `4` on every side, or
+///   `(top, right, bottom, left)`; added before the width is aligned to 8 pixels, and
+///   inside `canvas_height`
+/// - `max_width`: wrap lines to fit this many pixels, at whitespace or between CJK
+///   characters; the width is clamped to `max_width` rounded up to a multiple of 8
+/// - `max_lines`: keep at most this many lines, after wrapping; if there are more, the last
+///   line kept is shortened to fit the `ellipsis`, default `"…"`, within `max_width`, or
+///   within the widest line kept
+/// - `fit_width`, `fit_height`: instead of `font_size`, use the largest font size for which
+///   the image fits this many pixels, e.g. a fixed panel region for text of varying length;
+///   if it doesn't fit even at 6 pixels, a warning is emitted and the image clipped
+/// - `cell_height`: fixed height of each line, total height becomes `lines * cell_height`
+/// - `blank_line_height`: height of blank lines instead of the line height, e.g. a smaller
+///   paragraph gap, `0` drops them; line spacing still applies, ignored with `cell_height`
+/// - `canvas_height`: fixed height of the image, e.g. the panel height; the text block is
+///   placed by `valign` and clipped at the bottom if it is taller
+/// - `valign`: vertical alignment of a line inside its cell, and of the text block inside
+///   `canvas_height`, `top`, `middle` or `bottom`
+/// - `bidi`: reorder mixed LTR/RTL text into visual order, requires the `bidi` feature
+/// - `rtl`: right-to-left text, e.g. Arabic or Hebrew; lines are right-aligned unless
+///   `align = center`, and reordered with a right-to-left base direction, so embedded
+///   numbers and Latin runs keep their order; without the `bidi` feature each line is
+///   simply reversed, which is only correct for text without LTR runs
+/// - `normalize`: compose the text to Unicode NFC before layout, e.g. `e` followed by a
+///   combining accent becomes `é`, for fonts that don't position combining marks; requires
+///   the `normalize` feature
+/// - `pad_to`: zero-pad the emitted data to exactly this many bytes
+/// - `content_width`: expand to `(w, content_w, h, raw)`, `content_w` being the width of
+///   the text and padding before `w` was aligned to a byte, e.g. to center the image
+/// - `out_dir`: write the data to a file in `OUT_DIR` and expand to an `include_bytes!` of
+///   it instead of a byte string, which keeps large images from slowing down compilation;
+///   needs a build script, even an empty one, for cargo to set `OUT_DIR`
+/// - `as_array`: expand with the data as a `[u8; N]` array instead of a `&[u8]` byte string,
+///   for `const` items of a known size, e.g.
+///   `const LABEL: (u32, u32, [u8; 128]) = text_image!(..., as_array);`
+/// - `compress = "packbits"`: PackBits-compress the data, for mostly blank images in little
+///   flash; decompress it at runtime with `text_image_rt::decompress(raw, &mut buf)`, from
+///   the `no_std` crate `text-image-rt`, `buf` holding the uncompressed size, `stride * h` as
+///   given by `with_stride`, or `pad_to`
+/// - `with_stride`: expand to `(w, h, stride, raw)`, `stride` being the bytes per row, e.g.
+///   `w / 2` for Gray4, so framebuffer copies don't have to recompute it; for column and
+///   page scans it is the data size divided by the height
+/// - `const_fn`: emit `pub const fn <name>() -> ImageRaw<'static, C>` instead of a tuple,
+///   `C` is `BinaryColor`, `Gray2`, `Gray4` or `Gray8` according to the depth
+/// - `as_image_raw`: expand to an `ImageRaw<'static, C>` expression instead of a tuple, with
+///   `C` as for `const_fn`
+/// - `ligatures`: e.g. `[("ffi", 0xFB03), ("fi", 0xFB01)]`, sequences replaced by a ligature
+///   glyph before layout, in the given order
+/// - `require_chars`: e.g. `"àéîõü…"`, fail to compile unless the font has glyphs for all of
+///   these characters, whether or not `text` uses them; `require_chars_file` reads them from
+///   a UTF-8 file relative to the crate's `Cargo.toml`, whitespace is ignored
+/// - `axes`: e.g. `[("wght", 700.0), ("wdth", 75.0)]`, select an instance of a variable font;
+///   fails if the font is not variable or has no such axis
+/// - `offsets`: e.g. `[0, -2, 0, -2]`, vertical offset of each glyph of a line, cycled,
+///   negative moves up; the height grows by the extreme offsets so nothing clips
+/// - `origin_x`, `origin_y`: move the text by this many pixels, default 0; a positive
+///   `origin_x` widens the image by as much. Versions up to 0.2 drew text 1 pixel right
+///   and up, `origin_x = 1, origin_y = -1` restores that layout
+/// - `max_ascent`: clamp the ascent used for line height, in pixels; taller glyphs
+///   still render but may be clipped
+/// - `cap_metrics`: clamp the ascent used for line height to the cap height of the font
+/// - `leader`: e.g. `"."`, lay out `"Name:\tvalue"` with the value flush to the right margin,
+///   the gap filled with the leader; `""` leaves the gap blank. With `max_width`, such lines
+///   span the whole width
+/// - `align`: `left` (default), `center` or `right`, horizontal alignment of each line in the
+///   byte aligned width; `right` keeps an `origin_x` margin to the right edge
+/// - `align_mode`: `up` (default) or `nearest`, how the width is aligned to 8 pixels,
+///   e.g. a width of 65 becomes 72 or 64
+/// - `scan`: pixel scan order of 1-bit output, `row_msb` (default), `row_lsb`, `col_msb`,
+///   `col_lsb` or `page_vertical`, see [`Scan`](text_image_core::Scan) for the layouts;
+///   only `row_msb` data can be used as an `ImageRaw`. Scans other than `row_msb` are a
+///   compile error with Gray2, Gray4 or Gray8
+/// - `dither_edges`: for 1-bit output, turn anti-aliased glyph edges into an ordered dither
+///   pattern instead of thresholding them at 50%; glyph interiors stay solid
+/// - `dither`: for Gray2 and Gray4 output, Floyd-Steinberg error diffusion to the available
+///   levels instead of truncating, against banding of soft edges and gradients
+/// - `rounding`: for Gray2 and Gray4 output, round each pixel to the nearest level instead
+///   of keeping its high bits, which truncates and darkens midtones; off by default to keep
+///   the output bytes of earlier versions
+/// - `threshold`: for 1-bit output, the luma from which a pixel is set, default 128; lower
+///   keeps more of the anti-aliased edges, e.g. thin strokes at small sizes; ignored with
+///   `dither_edges` and other bit depths
+/// - `bold`: synthetic bold for fonts without a bold weight, each line is drawn again 1px to
+///   the right and 1px down, the image grows by 1px in both directions
+/// - `italic`: synthetic italic for fonts without an oblique style, glyphs are sheared right
+///   by 0.2px per pixel of height, the image grows by that much of the line height
+/// - `underline`, `strikethrough`: draw a line below the baseline, or through the middle of
+///   the x-height, as wide as the text of each line; the thickness and position follow the
+///   font's descent and x-height
+/// - `rotate`: rotate the rendered image clockwise by 90, 180 or 270 degrees, e.g. for a
+///   panel mounted in portrait; width and height are swapped for 90 and 270, and the new
+///   width is aligned to 8 pixels again
 /// - `inverse`: inverse color
-/// - `gray_depth`: Gray2, Gray4, Gray8
+/// - `background`, `foreground`: e.g. `background = 0xCC, foreground = 0x33`, background and
+///   text luma, default 0x00 and 0xFF; can not be combined with `inverse`, which is a
+///   shorthand for `background = 0xFF, foreground = 0x00`
+/// - `gray_depth`: Gray2, Gray4, Gray8, or `gray_depth = 4`; `gray_depth = env!("DISPLAY_DEPTH")`
+///   reads it from an environment variable at build time, e.g. set by a build script with
+///   `cargo:rustc-env=DISPLAY_DEPTH=4`, to target several panels from one source
+///
+/// Rendering is done by the `text-image-core` crate, which offers the same options as a
+/// `TextImageBuilder` for generating images at runtime.
+///
+/// Glyph coverage is quantized and blended by this crate itself, not by the rendering
+/// dependencies, so the same options produce the same bytes across patch releases of
+/// `ab_glyph` and `image`; golden output tests in `text-image-core` check this.
+///
+/// Characters of `text` none of the fonts has a glyph for are drawn as the font's `.notdef`
+/// glyph, often a blank box, and reported as a compiler warning listing them, e.g.
+/// `font Foo.ttf is missing glyphs: 😀, 𡃁`. Use `require_chars` to make this an error.
 ///
 /// Usage:
 ///
@@ -169,130 +1060,441 @@ impl Parse for TextImageOptions {
 ///
 /// fn main() {
 ///   let (w, h, raw) = text_image!(
-///     text = "Hello, world!哈哈这样也行",
-///     font = "LXGWWenKaiScreen.ttf",
+///     text = "Hello, world!",
+///     font = "core/tests/fixtures/DejaVuSans.ttf",
 ///     font_size = 48.0,
 ///     inverse,
-///     Gray4,
+///     Gray8,
 ///   );
 ///   let raw_image = ImageRaw::<Gray8>::new(raw, w);
+///   assert_eq!(raw.len() as u32, w * h);
 /// }
+/// ```
+///
+/// With `const_fn`, the macro expands to an item with the correct color type:
+///
+/// ```rust
+/// use embedded_graphics::{image::Image, mock_display::MockDisplay, pixelcolor::Gray4, prelude::*};
+///
+/// text_image::text_image!(
+///     text = "Hi",
+///     font = @dejavu,
+///     font_size = 16.0,
+///     Gray4,
+///     const_fn = hi_label,
+/// );
+///
+/// let mut display = MockDisplay::<Gray4>::new();
+/// Image::new(&hi_label(), Point::zero()).draw(&mut display).unwrap();
+/// assert_eq!(display.affected_area().size, hi_label().size());
+/// ```
+///
+/// Fonts used by many calls can be given an alias in a `text-image-fonts.txt` next to the
+/// crate's `Cargo.toml`, one `alias = path` per line, with paths relative to that file and
+/// `#` starting a comment line:
+///
+/// ```text
+/// # text-image-fonts.txt
+/// dejavu = core/tests/fixtures/DejaVuSans.ttf
+/// ```
+///
+/// Each call reads the file, so aliases don't depend on the order macros are expanded in.
+/// Unknown aliases are an error listing those in the file.
+///
+/// ```rust
+/// let (w, h, raw) = text_image::text_image!(
+///     text = "Hello, world!\nAVATAR fi",
+///     font = @dejavu,
+///     font_size = 16.0,
+///     Gray4,
+/// );
+/// assert_eq!((w, h, raw.len()), (88, 32, 88 * 32 / 2));
+/// ```
+///
+/// Column and page scans are only for 1-bit output:
 ///
-/// ````
+/// ```rust
+/// let (w, h, raw) = text_image::text_image!(text = "Hi", font = @dejavu, scan = col_msb);
+/// assert_eq!(raw.len() as u32, w * h.div_ceil(8));
+/// ```
+///
+/// ```compile_fail
+/// let (w, h, raw) = text_image::text_image!(text = "Hi", font = @dejavu, scan = col_msb, Gray4);
+/// ```
 #[proc_macro]
 pub fn text_image(input: TokenStream) -> TokenStream {
-    let opts = parse_macro_input!(input as TextImageOptions);
-    println!("text_image: {:#?}", opts);
-
-    let font_raw = std::fs::read(opts.font).expect("Can not read font file");
-    let font = FontRef::try_from_slice(&font_raw).expect("Can not load font");
-
-    let scale = PxScale {
-        x: opts.font_size,
-        y: opts.font_size,
+    let TextImageArgs {
+        opts,
+        output,
+        font_span,
+        fallback_spans,
+        content_width,
+    } = parse_macro_input!(input as TextImageArgs);
+    debug_log!("text_image: {:#?}", opts);
+    let font = match opts.font_data {
+        Some(_) => "`font_data`".to_string(),
+        None => opts.font.display().to_string(),
     };
+    let has_fallback = !opts.fallback_fonts.is_empty();
+
+    let gray_depth = opts.gray_depth;
+    // other scan orders are not an `ImageRaw` layout
+    let depth = if gray_depth == 1 && !opts.scan.is_image_raw() {
+        None
+    } else {
+        Some(gray_depth as i32)
+    };
+    let image = match build_text_image(opts, font_span, &fallback_spans) {
+        Ok(image) => image,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    debug_log!("text_image: result size {}x{}", image.width, image.height);
 
-    // let metric = font.v_metrics(scale);
-    let sfont = font.as_scaled(scale);
-    let line_height = (sfont.ascent() - sfont.descent() + sfont.line_gap())
-        .abs()
-        .ceil() as i32;
-
-    let mut h = 0;
-    let mut w = 0;
-    let mut lines = 0;
+    // TODO: binary support https://github.com/image-rs/image/issues/640
 
-    for line in opts.text.lines() {
-        let (lw, _lh) = text_size(scale, &font, line);
-        // println!("lh => {}", _lh);
-        w = w.max(lw);
-        h += line_height;
-        lines += 1;
+    let is_item = output.const_fn.is_some();
+    let mut expanded = expand_raw(image.width, image.height, depth, image.data, &output);
+    if content_width.is_some() {
+        let expanded_raw = proc_macro2::TokenStream::from(expanded);
+        let content_width = image.content_width;
+        expanded = quote! {{
+            let (w, h, raw) = #expanded_raw;
+            (w, #content_width, h, raw)
+        }}
+        .into();
     }
-    w += 1;
-    h += opts.line_spacing as i32 * (lines - 1);
-
-    // align to byte
-    if w % 8 != 0 {
-        w = (w / 8 + 1) * 8;
+    if image.clipped {
+        let message = format!(
+            "text doesn't fit `fit_width`/`fit_height` even at font size {}, the image is clipped",
+            MIN_FIT_SIZE
+        );
+        expanded = with_warning(expanded, &message, is_item);
     }
-    println!("text_image: result size {}x{}, {} lines", w, h, lines);
-
-    let mut image: image::ImageBuffer<Luma<u8>, Vec<u8>> = GrayImage::new(w as _, h as _);
-
-    let mut luma = 0xFF;
-    if opts.inverse {
-        image.fill(0xFF);
-        luma = 0x00;
+    if image.missing_glyphs.is_empty() {
+        return expanded;
     }
+    let chars: Vec<String> = image.missing_glyphs.iter().map(char::to_string).collect();
+    let message = format!(
+        "font {}{} is missing glyphs: {}",
+        font,
+        if has_fallback {
+            " and its fallback fonts"
+        } else {
+            ""
+        },
+        chars.join(", ")
+    );
+    with_warning(expanded, &message, is_item)
+}
 
-    for (i, line) in opts.text.lines().enumerate() {
-        // 1 px offset for blending
-        draw_text_mut(
-            &mut image,
-            Luma([luma]),
-            1,
-            (line_height + opts.line_spacing) * (i as i32) - 1,
-            scale,
-            &font,
-            &line,
-        );
-    }
+/// Render the text image, with errors pointing at the font option they are about
+fn build_text_image(
+    opts: TextImageOptions,
+    font_span: Option<proc_macro2::Span>,
+    fallback_spans: &[(PathBuf, proc_macro2::Span)],
+) -> Result<TextImage> {
+    TextImageBuilder::from(opts)
+        .build()
+        .map_err(|err| text_image_error(err, font_span, fallback_spans))
+}
 
-    let mut raw = image.into_raw();
+/// Measure the text image without drawing it, with errors as `build_text_image`
+fn measure_text_image(
+    opts: TextImageOptions,
+    font_span: Option<proc_macro2::Span>,
+    fallback_spans: &[(PathBuf, proc_macro2::Span)],
+) -> Result<(u32, u32)> {
+    TextImageBuilder::from(opts)
+        .measure()
+        .map_err(|err| text_image_error(err, font_span, fallback_spans))
+}
 
-    // gamma transform
-    if opts.gamma != 1.0 {
-        let gamma = opts.gamma;
-        for p in raw.iter_mut() {
-            let v = (*p as f32 / 255.0).powf(gamma) * 255.0;
-            *p = v as u8;
-        }
+/// An error of the renderer, pointing at the font option it is about
+fn text_image_error(
+    err: TextImageError,
+    font_span: Option<proc_macro2::Span>,
+    fallback_spans: &[(PathBuf, proc_macro2::Span)],
+) -> syn::Error {
+    let span = match &err {
+        TextImageError::ReadFont(path, _) | TextImageError::InvalidFont(path) => fallback_spans
+            .iter()
+            .find(|(fallback, _)| fallback == path)
+            .map(|(_, span)| *span)
+            .or(font_span),
+        TextImageError::NotVariableFont(_) | TextImageError::MissingGlyphs(..) => font_span,
+        _ => None,
+    };
+    syn::Error::new(span.unwrap_or_else(proc_macro2::Span::call_site), err)
+}
+
+/// Measure a text image without emitting its data.
+///
+/// Takes the options of [`text_image!`] and expands to `(w, h)`, the size `text_image!`
+/// gives the same options, including the alignment of the width to 8 pixels. The text is
+/// laid out as for `text_image!` but not drawn. Useful to lay out several elements at
+/// compile time:
+///
+/// ```rust
+/// use text_image::{text_image, text_image_size};
+///
+/// const TITLE: (u32, u32) = text_image_size!(
+///     text = "Settings",
+///     font = @dejavu,
+///     font_size = 24.0,
+///     Gray4,
+/// );
+///
+/// let (w, h, _) = text_image!(text = "Settings", font = @dejavu, font_size = 24.0, Gray4);
+/// assert_eq!(TITLE, (w, h));
+/// ```
+///
+/// Options that only change how the data is emitted, like `pad_to` or `const_fn`, are
+/// rejected.
+#[proc_macro]
+pub fn text_image_size(input: TokenStream) -> TokenStream {
+    let TextImageArgs {
+        opts,
+        output,
+        font_span,
+        fallback_spans,
+        content_width,
+    } = parse_macro_input!(input as TextImageArgs);
+    debug_log!("text_image_size: {:#?}", opts);
+    if output.pad_to.is_some()
+        || output.const_fn.is_some()
+        || output.image_raw.is_some()
+        || output.stride.is_some()
+        || output.out_dir.is_some()
+        || output.array.is_some()
+        || output.compress.is_some()
+        || content_width.is_some()
+    {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "`text_image_size!` expands to `(w, h)` and takes no `pad_to`, `const_fn`, \
+             `as_image_raw`, `with_stride`, `out_dir`, `as_array`, `compress` or `content_width`",
+        )
+        .to_compile_error()
+        .into();
     }
+    let (w, h) = match measure_text_image(opts, font_span, &fallback_spans) {
+        Ok(size) => size,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    debug_log!("text_image_size: {}x{}", w, h);
+    quote! { (#w, #h) }.into()
+}
 
-    // convert depth
-    let raw: Vec<u8> = match opts.gray_depth {
-        8 => raw,
-        4 => raw
-            .chunks(2)
-            .map(|ch| (ch[1] >> 4) | (ch[0] & 0xF0))
-            .collect(),
-        2 => {
-            let mut ret = Vec::with_capacity(raw.len() / 4);
-            for ch in raw.chunks(4) {
-                ret.push(
-                    (ch[3] >> 6) | ((ch[2] >> 4) & 0x0C) | ((ch[1] >> 2) & 0x30) | (ch[0] & 0xC0),
-                );
+/// Attach a compiler warning to an expansion, as proc macros can't emit warnings on stable.
+///
+/// The warning is the deprecation of an item used in an anonymous const, `is_item` tells
+/// whether `expanded` is an item or an expression.
+fn with_warning(expanded: TokenStream, message: &str, is_item: bool) -> TokenStream {
+    let expanded = proc_macro2::TokenStream::from(expanded);
+    let warning = quote! {
+        const _: () = {
+            #[deprecated(note = #message)]
+            struct Warning;
+            let _ = Warning;
+        };
+    };
+    if is_item {
+        quote! { #warning #expanded }.into()
+    } else {
+        quote! {{ #warning #expanded }}.into()
+    }
+}
+
+/// Options shared by all macros, controlling how the generated data is expanded
+#[derive(Debug, Default)]
+struct OutputOptions {
+    /// zero-pad the emitted data to this many bytes
+    pad_to: Option<LitInt>,
+    /// emit a `pub const fn` returning an `ImageRaw` instead of a tuple
+    const_fn: Option<Ident>,
+    /// expand to an `ImageRaw` expression instead of a tuple, the flag itself for its span
+    image_raw: Option<Ident>,
+    /// expand to `(w, h, stride, raw)`, the flag itself for its span
+    stride: Option<Ident>,
+    /// write the data to a file in `OUT_DIR` and include it, the flag itself for its span
+    out_dir: Option<Ident>,
+    /// emit the data as a `[u8; N]` array instead of a byte string, the flag itself for its
+    /// span
+    array: Option<Ident>,
+    /// PackBits-compress the data, the option itself for its span
+    compress: Option<Ident>,
+}
+
+impl OutputOptions {
+    fn parse_option(&mut self, name: &Ident, input: ParseStream) -> Result<()> {
+        match &*name.to_string() {
+            "pad_to" => {
+                input.parse::<Token![=]>()?;
+                self.pad_to = Some(input.parse()?);
             }
-            ret
-        }
-        1 => {
-            let mut ret = Vec::with_capacity(raw.len() / 8);
-            for ch in raw.chunks(8) {
-                ret.push(
-                    (ch[7] >> 7)
-                        | ((ch[6] >> 6) & 0x02)
-                        | ((ch[5] >> 5) & 0x04)
-                        | ((ch[4] >> 4) & 0x08)
-                        | ((ch[3] >> 3) & 0x10)
-                        | ((ch[2] >> 2) & 0x20)
-                        | ((ch[1] >> 1) & 0x40)
-                        | (ch[0] & 0x80),
-                );
+            "const_fn" => {
+                input.parse::<Token![=]>()?;
+                self.const_fn = Some(input.parse()?);
+            }
+            "as_image_raw" => self.image_raw = Some(name.clone()),
+            "with_stride" => self.stride = Some(name.clone()),
+            "out_dir" => self.out_dir = Some(name.clone()),
+            "as_array" => self.array = Some(name.clone()),
+            "compress" => {
+                input.parse::<Token![=]>()?;
+                let (value, span) = parse_keyword(input)?;
+                if value != "packbits" {
+                    return Err(syn::Error::new(span, "expected `packbits`"));
+                }
+                self.compress = Some(name.clone());
             }
-            ret
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
-    };
+        Ok(())
+    }
+}
 
-    // convert from 8-bit grayscale to 1-bit compressed bytes
+/// Write image data to a file in `OUT_DIR` named by its hash, returning the file name.
+///
+/// Identical data of repeated calls or builds reuses the file. The options don't name it:
+/// they refer to fonts, images and `env!` values whose contents can change, so the same
+/// options could name different data, and a build could include the file while an IDE's
+/// expansion of the edited call rewrites it. A file named by its data never changes.
+fn write_out_dir(raw: &[u8]) -> std::result::Result<String, String> {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let out_dir = std::env::var_os("OUT_DIR")
+        .ok_or("`out_dir` needs `OUT_DIR`, which cargo sets for crates with a build script")?;
+    let mut hasher = DefaultHasher::new();
+    raw.hash(&mut hasher);
+    let name = format!("text-image-{:016x}.bin", hasher.finish());
+    let path = Path::new(&out_dir).join(&name);
+    if std::fs::read(&path).ok().as_deref() != Some(raw) {
+        std::fs::write(&path, raw)
+            .map_err(|err| format!("Can not write {}: {}", path.display(), err))?;
+    }
+    debug_log!(
+        "text_image: wrote {} bytes to {}",
+        raw.len(),
+        path.display()
+    );
+    Ok(name)
+}
 
-    let raw_bytes = Lit::ByteStr(LitByteStr::new(&raw, proc_macro2::Span::call_site()));
+/// Expand to `(w, h, raw)`, `(w, h, stride, raw)` with `with_stride`, or an `ImageRaw` of
+/// the matching `depth` with `as_image_raw`, or a const fn returning one when `const_fn` is
+/// given. `raw` is a `[u8; N]` array with `as_array`, and PackBits-compressed with `compress`.
+///
+/// `depth` is None for data that isn't a plain grayscale image, e.g. palette indices.
+fn expand_raw(
+    w: u32,
+    h: u32,
+    depth: Option<i32>,
+    mut raw: Vec<u8>,
+    output: &OutputOptions,
+) -> TokenStream {
+    // rows start on byte boundaries, bytes per row, before padding
+    let stride = raw.len() as u32 / h.max(1);
+    if let Some(pad_to) = &output.pad_to {
+        let size: usize = match pad_to.base10_parse() {
+            Ok(size) => size,
+            Err(err) => return err.to_compile_error().into(),
+        };
+        if raw.len() > size {
+            return syn::Error::new_spanned(
+                pad_to,
+                format!("image data is {} bytes, exceeds pad_to", raw.len()),
+            )
+            .to_compile_error()
+            .into();
+        }
+        raw.resize(size, 0);
+    }
+    if output.compress.is_some() {
+        raw = compress(&raw);
+    }
 
-    let w = w as u32;
-    let h = h as u32;
+    let raw_bytes = match &output.out_dir {
+        // `include_bytes!` is a `&[u8; N]`
+        Some(out_dir) if output.array.is_some() => match write_out_dir(&raw) {
+            Ok(name) => quote! {
+                *include_bytes!(concat!(env!("OUT_DIR"), "/", #name))
+            },
+            Err(err) => {
+                return syn::Error::new_spanned(out_dir, err)
+                    .to_compile_error()
+                    .into()
+            }
+        },
+        Some(out_dir) => match write_out_dir(&raw) {
+            Ok(name) => quote! {
+                include_bytes!(concat!(env!("OUT_DIR"), "/", #name))
+            },
+            Err(err) => {
+                return syn::Error::new_spanned(out_dir, err)
+                    .to_compile_error()
+                    .into()
+            }
+        },
+        None if output.array.is_some() => quote! { [#(#raw),*] },
+        None => {
+            let raw_bytes = Lit::ByteStr(LitByteStr::new(&raw, proc_macro2::Span::call_site()));
+            quote! { #raw_bytes }
+        }
+    };
 
-    // TODO: binary support https://github.com/image-rs/image/issues/640
+    if let Some(option) = output.const_fn.as_ref().or(output.image_raw.as_ref()) {
+        let other = [&output.stride, &output.array, &output.compress]
+            .into_iter()
+            .flatten()
+            .next();
+        if let Some(other) = other {
+            return syn::Error::new_spanned(
+                other,
+                format!("`{}` can not be combined with `{}`", other, option),
+            )
+            .to_compile_error()
+            .into();
+        }
+        let color = match depth {
+            Some(1) => quote! { ::embedded_graphics::pixelcolor::BinaryColor },
+            Some(2) => quote! { ::embedded_graphics::pixelcolor::Gray2 },
+            Some(4) => quote! { ::embedded_graphics::pixelcolor::Gray4 },
+            Some(8) => quote! { ::embedded_graphics::pixelcolor::Gray8 },
+            _ => {
+                let name = if output.const_fn.is_some() {
+                    "const_fn"
+                } else {
+                    "as_image_raw"
+                };
+                return syn::Error::new_spanned(
+                    option,
+                    format!(
+                        "{} is only supported for grayscale or monochrome output",
+                        name
+                    ),
+                )
+                .to_compile_error()
+                .into();
+            }
+        };
+        let expanded = if let Some(const_fn) = &output.const_fn {
+            quote! {
+                pub const fn #const_fn() -> ::embedded_graphics::image::ImageRaw<'static, #color> {
+                    ::embedded_graphics::image::ImageRaw::new(#raw_bytes, #w)
+                }
+            }
+        } else {
+            quote! {
+                ::embedded_graphics::image::ImageRaw::<#color>::new(#raw_bytes, #w)
+            }
+        };
+        return TokenStream::from(expanded);
+    }
+
+    if output.stride.is_some() {
+        return quote! { (#w, #h, #stride, #raw_bytes) }.into();
+    }
 
     let expanded = quote! {
         (#w, #h, #raw_bytes)
@@ -303,22 +1505,99 @@ pub fn text_image(input: TokenStream) -> TokenStream {
 
 #[derive(Debug)]
 struct ImageOptions {
-    image: String,
+    image: PathBuf,
+    image_span: proc_macro2::Span,
+    /// image used when `image` can not be read
+    fallback: Option<LitStr>,
     /// index of the channel to use
     channel: u8,
     /// gray depth, 1, 2, 4, 8
     gray_depth: i32,
     /// gamma correction
     gamma: f32,
+    /// round Gray2 and Gray4 pixels to the nearest level instead of truncating
+    rounding: bool,
+    /// emit one palette index byte per pixel instead of packed bits
+    index_buffer: bool,
+    /// emit black and red planes interleaved byte by byte
+    interleave: bool,
+    /// set the bits of pixels not of the channel instead
+    invert: bool,
+    /// emit the black and red planes as two arrays, the flag itself for its span
+    planes: Option<Ident>,
+    /// emit an array of one plane per palette entry, the flag itself for its span
+    all_planes: Option<Ident>,
+    /// 0xRRGGBB color treated as transparent background
+    color_key: Option<u32>,
+    /// max per-channel difference to still match `color_key`
+    key_tolerance: u32,
+    /// 0xRRGGBB color transparent pixels are composited over
+    background: Option<u32>,
+    /// x, y, width and height of the part of the image used
+    crop: Option<[u32; 4]>,
+    crop_span: Option<proc_macro2::Span>,
+    /// width and height the image is resized to
+    resize: Option<(u32, u32)>,
+    /// resampling filter of `resize`
+    filter: FilterType,
+    /// clockwise rotation in degrees, after resizing
+    rotate: u32,
+    /// mirror left to right, after rotating
+    flip_h: bool,
+    /// mirror top to bottom, after rotating
+    flip_v: bool,
+    /// pixel scan order of 1-bit planes
+    scan: Scan,
+    scan_span: Option<proc_macro2::Span>,
+    /// color distance for palette mapping and dithering
+    metric: ColorMetric,
+    /// how colors between the palette entries are approximated
+    dither: Dither,
+    /// size of the Bayer matrix of ordered dithering, 2, 4 or 8
+    bayer_size: u32,
+    /// palette as 0xRRGGBB colors, empty for the macro default
+    palette: Vec<u32>,
+    /// write the palette-mapped image as an indexed PNG for review
+    save_indexed_png: Option<LitStr>,
+    /// white pixels of this image are mapped to the nearest color without dithering
+    no_dither_mask: Option<LitStr>,
+    output: OutputOptions,
 }
 
 impl Parse for ImageOptions {
     fn parse(input: ParseStream) -> Result<Self> {
         let mut opts = ImageOptions {
-            image: "".to_string(),
+            image: PathBuf::new(),
+            image_span: proc_macro2::Span::call_site(),
+            fallback: None,
             channel: 0,
             gray_depth: 1,
             gamma: 1.0,
+            rounding: false,
+            index_buffer: false,
+            interleave: false,
+            invert: false,
+            planes: None,
+            all_planes: None,
+            color_key: None,
+            key_tolerance: 0,
+            background: None,
+            crop: None,
+            crop_span: None,
+            resize: None,
+            filter: FilterType::Triangle,
+            rotate: 0,
+            flip_h: false,
+            flip_v: false,
+            scan: Scan::RowMsb,
+            scan_span: None,
+            metric: ColorMetric::L2,
+            dither: Dither::Floyd,
+            bayer_size: 8,
+            palette: vec![],
+            save_indexed_png: None,
+            no_dither_mask: None,
+            output: OutputOptions::default(),
         };
 
         let name: Lit = input.parse()?;
@@ -331,14 +1610,16 @@ impl Parse for ImageOptions {
                 "expected a string literal",
             ));
         };
-        opts.image = image;
+        opts.image = resolve_path(&image);
+        opts.image_span = name.span();
 
-        while let Ok(_) = input.parse::<Token![,]>() {
+        while input.parse::<Token![,]>().is_ok() {
             if input.is_empty() {
                 break;
             }
 
             let name: Ident = input.parse()?;
+            check_option(&name, &[IMAGE_OPTIONS, OUTPUT_OPTIONS, GRAY_OPTIONS])?;
 
             match &*name.to_string() {
                 "channel" => {
@@ -370,6 +1651,179 @@ impl Parse for ImageOptions {
                     }
                     opts.gamma = gamma;
                 }
+                "rounding" => {
+                    opts.rounding = true;
+                }
+                "index_buffer" => {
+                    opts.index_buffer = true;
+                }
+                "interleave" => {
+                    opts.interleave = true;
+                }
+                "invert" => {
+                    opts.invert = true;
+                }
+                "planes" => {
+                    opts.planes = Some(name.clone());
+                }
+                "all_planes" => {
+                    opts.all_planes = Some(name.clone());
+                }
+                "color_key" => {
+                    input.parse::<Token![=]>()?;
+                    let color_key: LitInt = input.parse()?;
+                    let value: u32 = color_key.base10_parse()?;
+                    if value > 0xFFFFFF {
+                        return Err(syn::Error::new_spanned(
+                            color_key,
+                            "expected a 0xRRGGBB color",
+                        ));
+                    }
+                    opts.color_key = Some(value);
+                }
+                "background" => {
+                    input.parse::<Token![=]>()?;
+                    let background: LitInt = input.parse()?;
+                    let value: u32 = background.base10_parse()?;
+                    if value > 0xFFFFFF {
+                        return Err(syn::Error::new_spanned(
+                            background,
+                            "expected a 0xRRGGBB color",
+                        ));
+                    }
+                    opts.background = Some(value);
+                }
+                "crop" => {
+                    input.parse::<Token![=]>()?;
+                    let Ok(crop) = <[u32; 4]>::try_from(parse_u32_tuple(input)?) else {
+                        return Err(syn::Error::new_spanned(
+                            name,
+                            "expected `crop = (x, y, width, height)`",
+                        ));
+                    };
+                    if crop[2] == 0 || crop[3] == 0 {
+                        return Err(syn::Error::new_spanned(name, "crop to an empty image"));
+                    }
+                    opts.crop = Some(crop);
+                    opts.crop_span = Some(name.span());
+                }
+                "resize" => {
+                    input.parse::<Token![=]>()?;
+                    let sizes = parse_u32_tuple(input)?;
+                    let [w, h] = sizes[..] else {
+                        return Err(syn::Error::new_spanned(
+                            name,
+                            "expected `resize = (width, height)`",
+                        ));
+                    };
+                    if w == 0 || h == 0 {
+                        return Err(syn::Error::new_spanned(name, "resize to an empty image"));
+                    }
+                    opts.resize = Some((w, h));
+                }
+                "filter" => {
+                    input.parse::<Token![=]>()?;
+                    let (value, span) = parse_keyword(input)?;
+                    opts.filter = match &*value {
+                        "nearest" => FilterType::Nearest,
+                        "triangle" => FilterType::Triangle,
+                        "lanczos" => FilterType::Lanczos3,
+                        _ => {
+                            return Err(syn::Error::new(
+                                span,
+                                "expected `nearest`, `triangle` or `lanczos`",
+                            ))
+                        }
+                    };
+                }
+                "rotate" => {
+                    input.parse::<Token![=]>()?;
+                    let rotate: LitInt = input.parse()?;
+                    opts.rotate = rotate.base10_parse()?;
+                    if ![0, 90, 180, 270].contains(&opts.rotate) {
+                        return Err(syn::Error::new_spanned(
+                            rotate,
+                            "expected a rotation of 0, 90, 180 or 270",
+                        ));
+                    }
+                }
+                "flip_h" => {
+                    opts.flip_h = true;
+                }
+                "flip_v" => {
+                    opts.flip_v = true;
+                }
+                "key_tolerance" => {
+                    input.parse::<Token![=]>()?;
+                    let key_tolerance: LitInt = input.parse()?;
+                    opts.key_tolerance = key_tolerance.base10_parse()?;
+                }
+                "metric" => {
+                    input.parse::<Token![=]>()?;
+                    opts.metric = parse_keyword_as(input)?;
+                }
+                "dither" => {
+                    input.parse::<Token![=]>()?;
+                    opts.dither = parse_keyword_as(input)?;
+                }
+                "bayer_size" => {
+                    input.parse::<Token![=]>()?;
+                    let bayer_size: LitInt = input.parse()?;
+                    opts.bayer_size = bayer_size.base10_parse()?;
+                    if ![2, 4, 8].contains(&opts.bayer_size) {
+                        return Err(syn::Error::new_spanned(
+                            bayer_size,
+                            "expected a Bayer matrix size of 2, 4 or 8",
+                        ));
+                    }
+                }
+                "scan" => {
+                    input.parse::<Token![=]>()?;
+                    opts.scan = parse_keyword_as(input)?;
+                    opts.scan_span = Some(name.span());
+                }
+                "palette" if input.peek2(LitStr) => {
+                    input.parse::<Token![=]>()?;
+                    opts.palette = palette_preset(&input.parse()?)?;
+                }
+                "palette" => {
+                    input.parse::<Token![=]>()?;
+                    let content;
+                    syn::bracketed!(content in input);
+                    let colors = content.parse_terminated(LitInt::parse, Token![,])?;
+                    for color in colors {
+                        let value: u32 = color.base10_parse()?;
+                        if value > 0xFFFFFF {
+                            return Err(syn::Error::new_spanned(
+                                color,
+                                "expected a 0xRRGGBB color",
+                            ));
+                        }
+                        opts.palette.push(value);
+                    }
+                    if opts.palette.is_empty() {
+                        return Err(syn::Error::new_spanned(name, "palette must not be empty"));
+                    }
+                }
+                option if OUTPUT_OPTIONS.contains(&option) => {
+                    opts.output.parse_option(&name, input)?;
+                }
+                "fallback" => {
+                    input.parse::<Token![=]>()?;
+                    opts.fallback = Some(input.parse()?);
+                }
+                "save_indexed_png" => {
+                    input.parse::<Token![=]>()?;
+                    opts.save_indexed_png = Some(input.parse()?);
+                }
+                "no_dither_mask" => {
+                    input.parse::<Token![=]>()?;
+                    opts.no_dither_mask = Some(input.parse()?);
+                }
+                "gray_depth" => {
+                    input.parse::<Token![=]>()?;
+                    opts.gray_depth = parse_gray_depth(input)? as i32;
+                }
                 "Gray2" => {
                     opts.gray_depth = 2;
                 }
@@ -379,255 +1833,1300 @@ impl Parse for ImageOptions {
                 "Gray8" => {
                     opts.gray_depth = 8;
                 }
-                _ => {
-                    return Err(syn::Error::new_spanned(
-                        name,
-                        "expected `palette` or `channel`",
-                    ));
-                }
+                // names not in the option lists were rejected by `check_option`
+                _ => unreachable!(),
             }
         }
 
+        check_scan(
+            opts.scan,
+            opts.scan_span,
+            opts.gray_depth,
+            opts.index_buffer,
+        )?;
+
         Ok(opts)
     }
 }
 
-struct BWR;
-
-impl BWR {
-    fn map_palette(&self, c: &Rgb<u8>) -> u8 {
-        let palette = vec![0x000000, 0xFFFFFF, 0xFF0000];
-        let mut min = 0;
-        let mut min_dist = 0x7FFF_FFFF;
-        for (i, p) in palette.iter().enumerate() {
-            let dist = (c.0[0] as i32 - (p >> 16) as i32).pow(2)
-                + (c.0[1] as i32 - ((p >> 8) & 0xFF) as i32).pow(2)
-                + (c.0[2] as i32 - (p & 0xFF) as i32).pow(2);
-            if dist < min_dist {
-                min_dist = dist;
-                min = i;
-            }
+impl ImageOptions {
+    /// Reject `scan` in macros which only emit row-major data
+    fn reject_scan(&self, macro_name: &str) -> Result<()> {
+        match self.scan_span.filter(|_| self.scan != Scan::RowMsb) {
+            Some(span) => Err(syn::Error::new(
+                span,
+                format!("scan is not supported by {}!", macro_name),
+            )),
+            None => Ok(()),
         }
-        min as u8
     }
 }
 
-impl image::imageops::colorops::ColorMap for BWR {
-    type Color = Rgb<u8>;
+/// Open `opts.image`, or `opts.fallback` if the former can not be read.
+fn open_image(opts: &ImageOptions) -> Result<image::DynamicImage> {
+    let err = match image::open(&opts.image) {
+        Ok(im) => {
+            debug_log!("text_image: using image {}", opts.image.display());
+            return prepare_image(im, opts);
+        }
+        Err(err) => err,
+    };
+
+    let Some(fallback) = &opts.fallback else {
+        return Err(syn::Error::new(
+            opts.image_span,
+            format!("Can not read image file: {}", err),
+        ));
+    };
+    match image::open(resolve_path(&fallback.value())) {
+        Ok(im) => {
+            debug_log!(
+                "text_image: can not read image {} ({}), using fallback {}",
+                opts.image.display(),
+                err,
+                fallback.value()
+            );
+            prepare_image(im, opts)
+        }
+        Err(fallback_err) => Err(syn::Error::new_spanned(
+            fallback,
+            format!(
+                "Can not read image file: {}, nor fallback: {}",
+                err, fallback_err
+            ),
+        )),
+    }
+}
 
-    fn index_of(&self, color: &Self::Color) -> usize {
-        let palette = vec![0x000000, 0xFFFFFF, 0xFF0000];
-        let mut min = 0;
-        let mut min_dist = 0x7FFF_FFFF;
-        for (i, p) in palette.iter().enumerate() {
-            let dist = (color.0[0] as i32 - (p >> 16) as i32).pow(2)
-                + (color.0[1] as i32 - ((p >> 8) & 0xFF) as i32).pow(2)
-                + (color.0[2] as i32 - (p & 0xFF) as i32).pow(2);
-            if dist < min_dist {
-                min_dist = dist;
-                min = i;
+/// Crop, composite, resize, rotate and flip an image read, in that order
+fn prepare_image(im: image::DynamicImage, opts: &ImageOptions) -> Result<image::DynamicImage> {
+    let im = match opts.crop {
+        Some([x, y, w, h]) => {
+            let (width, height) = im.dimensions();
+            if x as u64 + w as u64 > width as u64 || y as u64 + h as u64 > height as u64 {
+                return Err(syn::Error::new(
+                    opts.crop_span.unwrap(),
+                    format!(
+                        "crop ({}, {}, {}, {}) exceeds the image of {}x{}",
+                        x, y, w, h, width, height
+                    ),
+                ));
             }
+            im.crop_imm(x, y, w, h)
         }
-        min
+        None => im,
+    };
+    let im = composite(im, opts.background);
+    let im = match opts.resize {
+        Some((w, h)) => im.resize_exact(w, h, opts.filter),
+        None => im,
+    };
+    let im = match opts.rotate {
+        90 => im.rotate90(),
+        180 => im.rotate180(),
+        270 => im.rotate270(),
+        _ => im,
+    };
+    let im = if opts.flip_h { im.fliph() } else { im };
+    Ok(if opts.flip_v { im.flipv() } else { im })
+}
+
+/// Blend the pixels of an image with alpha over `background`, fully transparent ones
+/// become the background.
+///
+/// Without a background the alpha is dropped as is, keeping the color of transparent pixels,
+/// often black.
+fn composite(im: image::DynamicImage, background: Option<u32>) -> image::DynamicImage {
+    let Some(background) = background else {
+        return im;
+    };
+    if !im.color().has_alpha() {
+        return im;
     }
-    fn map_color(&self, color: &mut Self::Color) {
-        let idx = self.index_of(color);
-        let palette = [
-            Rgb([0x00, 0x00, 0x00]),
-            Rgb([0xFF, 0xFF, 0xFF]),
-            Rgb([0xFF, 0x00, 0x00]),
-        ];
-        *color = palette[idx];
+    let bg = [
+        (background >> 16) as u8,
+        (background >> 8) as u8,
+        background as u8,
+    ];
+    let rgba = im.to_rgba8();
+    let rgb = image::RgbImage::from_fn(im.width(), im.height(), |x, y| {
+        let px = rgba.get_pixel(x, y).0;
+        let alpha = px[3] as u32;
+        Rgb([0, 1, 2]
+            .map(|i| ((px[i] as u32 * alpha + bg[i] as u32 * (255 - alpha) + 127) / 255) as u8))
+    });
+    image::DynamicImage::ImageRgb8(rgb)
+}
+
+/// Dither `im` to the colors of `map` with the `dither` algorithm, except where the
+/// `no_dither_mask` image is white: those pixels are mapped to the nearest color, and neither
+/// take nor spread any error.
+fn dither_image(im: &mut image::RgbImage, map: &PaletteMap, opts: &ImageOptions) -> Result<()> {
+    let Some(mask_path) = &opts.no_dither_mask else {
+        dither(im, map, opts.dither, opts.bayer_size, None);
+        return Ok(());
+    };
+    let mask = image::open(resolve_path(&mask_path.value()))
+        .map_err(|err| {
+            syn::Error::new_spanned(mask_path, format!("Can not read mask image: {}", err))
+        })?
+        .to_luma8();
+    if mask.dimensions() != im.dimensions() {
+        return Err(syn::Error::new_spanned(
+            mask_path,
+            format!(
+                "mask is {}x{}, the image is {}x{}",
+                mask.width(),
+                mask.height(),
+                im.width(),
+                im.height()
+            ),
+        ));
     }
+    dither(im, map, opts.dither, opts.bayer_size, Some(&mask));
+    Ok(())
 }
 
+/// Write the dithered image as an 8-bit indexed PNG with exactly `palette`, for review.
+///
+/// `path` is relative to the crate manifest. This is a development aid, failures are only
+/// logged and never fail the build.
+fn save_indexed_png(
+    path: &LitStr,
+    im: &image::RgbImage,
+    palette: &[u32],
+    map_palette: impl Fn(&Rgb<u8>) -> u8,
+) {
+    let path = resolve_path(&path.value());
+    let indices: Vec<u8> = im.pixels().map(map_palette).collect();
+    let rgb: Vec<u8> = palette
+        .iter()
+        .flat_map(|c| [(c >> 16) as u8, (c >> 8) as u8, *c as u8])
+        .collect();
+
+    let write = || -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        let mut encoder = png::Encoder::new(file, im.width(), im.height());
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(rgb);
+        encoder.write_header()?.write_image_data(&indices)?;
+        Ok(())
+    };
+    match write() {
+        Ok(()) => debug_log!("text_image: saved indexed png {}", path.display()),
+        Err(err) => debug_log!(
+            "text_image: can not save indexed png {}: {}",
+            path.display(),
+            err
+        ),
+    }
+}
+
+/// Image reading macro for BWR palette, emitting one bit per pixel for the selected `channel`.
+///
+/// `palette = [0x000000, 0xFFFFFF, 0xFF0000]` overrides the default black, white, red, e.g.
+/// for black-white-yellow or two-color panels; `channel` is an index into the palette.
+/// Palettes of common panels are available by name: `palette = "bw"`, `"bwr"`, `"bwyr"` or
+/// `"acep7"`, also for `acep_image!`.
+///
+/// With `index_buffer`, one palette index byte per pixel is emitted instead of the packed plane.
+///
+/// With `interleave`, the black and red planes are dithered once and emitted interleaved byte
+/// by byte, as some BWR controllers expect: `B0, R0, B1, R1, ...`, where `Bn`/`Rn` is the n-th
+/// byte of the black/red plane, i.e. what `channel = 0`/`channel = 2` would emit. The data is
+/// twice the size of a single plane.
+///
+/// With `planes`, the image is dithered once and both planes of tri-color panels are
+/// emitted, `(w, h, black, red)`, i.e. what `channel = 0` and `channel = 2` would emit, but
+/// consistent with each other and read and dithered only once.
+///
+/// With `all_planes`, one plane per palette entry is emitted in palette order,
+/// `(w, h, [plane0, plane1, ...])`, e.g. for controllers with a plane per color.
+///
+/// With `invert`, the bits of all pixels not of the channel are set instead, for panels
+/// where a set bit means no ink on the plane. Padding bits at the end of rows stay zero.
+///
+/// `background = 0xFFFFFF` composites transparent pixels over that color, e.g. for icons
+/// with transparency; without it the alpha channel is dropped. Also supported by the other
+/// image macros.
+///
+/// `crop = (x, y, width, height)` uses only that part of the image, e.g. one sprite of a
+/// sheet, and fails if it exceeds the image. It is applied first, the returned size is the
+/// cropped one. Also supported by the other image macros.
+///
+/// `resize = (300, 240)` scales the image to that size before dithering, e.g. art made for
+/// a larger panel, with `filter = nearest | triangle | lanczos`, default `triangle`. The
+/// returned size is the new one, and a `no_dither_mask` must have it. Also supported by the
+/// other image macros.
+///
+/// `rotate = 90 | 180 | 270` rotates the image clockwise, then `flip_h` and `flip_v` mirror
+/// it left to right and top to bottom, for panels mounted sideways or mirrored. The
+/// returned size is the rotated one. Also supported by the other image macros.
+///
+/// `color_key = 0xFF00FF` treats pixels of that color as transparent background, e.g. for
+/// magenta-keyed sprites: they are dithered as white and never inked, so their bits are clear
+/// in the emitted plane, or set with `invert`.
+/// `key_tolerance = 16` also matches colors differing by up to 16 in each channel.
+///
+/// `scan = row_msb | row_lsb | col_msb | col_lsb | page_vertical` selects the pixel scan order
+/// of the plane, see [`Scan`](text_image_core::Scan). Row scans round the width up to 8
+/// pixels, column and page scans pad each column to whole bytes instead and keep the width.
+/// `scan` can not be combined with `index_buffer`, and is not supported by the other image
+/// macros.
+///
+/// `fallback = "placeholder.png"` is used instead when the image can not be read, e.g. for
+/// assets missing from some build configurations.
+///
+/// `metric = l1 | l2 | lab` selects Manhattan or Euclidean color distance used for palette
+/// classification and dithering, default `l2`. Both agree as long as every palette entry is
+/// a corner of the RGB cube, they differ for palettes with intermediate colors like orange.
+/// `lab` measures ΔE in CIELAB instead, slower but closer to perceived color, e.g. for the
+/// dense palettes of 7-color panels.
+///
+/// `dither = floyd | atkinson | ordered | none` selects Floyd-Steinberg error diffusion
+/// (default), Atkinson error diffusion, with more contrast, a Bayer matrix, which keeps
+/// flat regions clean, or no dithering, each pixel becomes the nearest palette color.
+/// `floyd-serpentine` is Floyd-Steinberg alternating the direction of rows, against the
+/// diagonal "worm" patterns of plain Floyd-Steinberg on gradients and photos.
+/// `bayer` is another name for `ordered`, `bayer_size = 2 | 4 | 8` sets the size of the
+/// matrix, default 8; smaller matrices give a coarser but less busy pattern. Also
+/// supported by `quadcolor_image!` and `acep_image!`.
+///
+/// `no_dither_mask = "mask.png"` limits dithering to part of the image, e.g. a photo next to
+/// flat UI: where the mask is white, pixels are mapped to the nearest palette color, where it
+/// is black they are dithered. The mask must have the size of the image. Also supported by
+/// `quadcolor_image!` and `acep_image!`.
+///
+/// `save_indexed_png = "review/logo.png"` also writes the dithered result as an indexed PNG
+/// with the exact palette, relative to the crate manifest, so reviewers see the true colors.
+/// It doesn't change the emitted data, and write errors are ignored. Also supported by
+/// `quadcolor_image!` and `acep_image!`.
+///
+/// ```
+/// // black at x = 0, 8 and 9, red at x = 1 and 15
+/// let (w, h, red) = text_image::monochrome_image!("tests/fixtures/bwr16x1.png", channel = 2);
+/// assert_eq!((w, h, red), (16, 1, &[0x40, 0x01]));
+/// // or, for custom drivers
+/// let (_, _, indices) = text_image::monochrome_image!("tests/fixtures/bwr16x1.png", index_buffer);
+/// assert_eq!(indices.len(), 16);
+/// ```
 #[proc_macro]
 pub fn monochrome_image(input: TokenStream) -> TokenStream {
     let opts = parse_macro_input!(input as ImageOptions);
-    println!("text_image: {:#?}", opts);
+    debug_log!("text_image: {:#?}", opts);
 
-    let im = image::open(&opts.image).expect("Can not read image file");
+    let im = match open_image(&opts) {
+        Ok(im) => im,
+        Err(err) => return err.to_compile_error().into(),
+    };
     let (mut w, h) = im.dimensions();
 
     let mut im = im.to_rgb8();
 
-    // Floyd-Steinberg dithering
-    image::imageops::colorops::dither(&mut im, &BWR);
-
-    let mut ret = vec![];
-
-    // convert each 8 pixel to a compressed byte
-    for (_y, row) in im.enumerate_rows() {
-        let mut n = 0u8;
-        for (x, (_, _, px)) in row.enumerate() {
-            let ix = BWR.map_palette(px);
-            if ix == opts.channel {
-                n |= 1 << (7 - x % 8);
-            }
-            if x % 8 == 7 {
-                ret.push(n);
-                n = 0;
-            }
-        }
-        if w % 8 != 0 {
-            ret.push(n);
+    // pixels matching the color key are background, never inked in a plane
+    let keyed: Option<Vec<bool>> = opts.color_key.map(|key| {
+        let key = [(key >> 16) as u8, (key >> 8) as u8, key as u8];
+        im.pixels_mut()
+            .map(|px| {
+                let is_key = (0..3).all(|i| px.0[i].abs_diff(key[i]) as u32 <= opts.key_tolerance);
+                if is_key {
+                    *px = Rgb([0xFF, 0xFF, 0xFF]);
+                }
+                is_key
+            })
+            .collect()
+    });
+
+    let palette = if opts.palette.is_empty() {
+        BWR_PALETTE.to_vec()
+    } else {
+        opts.palette.clone()
+    };
+    if let Some(planes) = opts.planes.as_ref().or(opts.all_planes.as_ref()) {
+        let output = &opts.output;
+        if opts.interleave
+            || opts.index_buffer
+            || (opts.planes.is_some() && opts.all_planes.is_some())
+            || output.pad_to.is_some()
+            || output.stride.is_some()
+            || output.out_dir.is_some()
+            || output.array.is_some()
+            || output.compress.is_some()
+            || output.const_fn.is_some()
+            || output.image_raw.is_some()
+        {
+            return syn::Error::new_spanned(
+                planes,
+                format!(
+                    "`{}` can not be combined with `interleave`, `index_buffer`, `planes`, \
+                     `all_planes`, `pad_to`, `with_stride`, `out_dir`, `as_array`, `compress`, \
+                     `const_fn` or `as_image_raw`",
+                    planes
+                ),
+            )
+            .to_compile_error()
+            .into();
         }
     }
-
-    w = (w / 8 + if w % 8 != 0 { 1 } else { 0 }) * 8;
-
-    let raw_bytes = Lit::ByteStr(LitByteStr::new(&ret, proc_macro2::Span::call_site()));
-
-    let expanded = quote! {
-        (#w, #h, #raw_bytes)
+    // interleave and planes emit the planes of index 0 and 2
+    let max_channel = if opts.interleave || opts.planes.is_some() {
+        2
+    } else {
+        opts.channel
+    };
+    if max_channel as usize >= palette.len() {
+        return syn::Error::new(
+            opts.image_span,
+            format!(
+                "channel {} is not in the palette of {} colors",
+                max_channel,
+                palette.len()
+            ),
+        )
+        .to_compile_error()
+        .into();
+    }
+    let map = PaletteMap {
+        palette,
+        metric: opts.metric,
     };
 
-    TokenStream::from(expanded)
-}
+    if let Err(err) = dither_image(&mut im, &map, &opts) {
+        return err.to_compile_error().into();
+    }
 
-struct BWYR;
+    if let Some(path) = &opts.save_indexed_png {
+        save_indexed_png(path, &im, &map.palette, |px| map.map_palette(px));
+    }
 
-impl BWYR {
-    fn map_palette(&self, c: &Rgb<u8>) -> u8 {
-        let palette = vec![0x000000, 0xFFFFFF, 0xFF0000, 0xFFFF00];
-        let mut min = 0;
-        let mut min_dist = 0x7FFF_FFFF;
-        for (i, p) in palette.iter().enumerate() {
-            let dist = (c.0[0] as i32 - (p >> 16) as i32).pow(2)
-                + (c.0[1] as i32 - ((p >> 8) & 0xFF) as i32).pow(2)
-                + (c.0[2] as i32 - (p & 0xFF) as i32).pow(2);
-            if dist < min_dist {
-                min_dist = dist;
-                min = i;
-            }
+    if opts.index_buffer {
+        // one palette index per pixel, no bit packing
+        let ret: Vec<u8> = im.pixels().map(|px| map.map_palette(px)).collect();
+        return expand_raw(w, h, None, ret, &opts.output);
+    }
+
+    if opts.planes.is_some() {
+        // both planes of the same dithered image
+        let black = pack_channel(&im, &map, 0, keyed.as_deref(), &opts);
+        let red = pack_channel(&im, &map, 2, keyed.as_deref(), &opts);
+        if matches!(opts.scan, Scan::RowMsb | Scan::RowLsb) {
+            w = w.div_ceil(8) * 8;
         }
-        min as u8
+        let black = Lit::ByteStr(LitByteStr::new(&black, proc_macro2::Span::call_site()));
+        let red = Lit::ByteStr(LitByteStr::new(&red, proc_macro2::Span::call_site()));
+        return quote! { (#w, #h, #black, #red) }.into();
+    }
+    if opts.all_planes.is_some() {
+        let planes: Vec<Lit> = (0..map.palette.len() as u8)
+            .map(|channel| {
+                let plane = pack_channel(&im, &map, channel, keyed.as_deref(), &opts);
+                Lit::ByteStr(LitByteStr::new(&plane, proc_macro2::Span::call_site()))
+            })
+            .collect();
+        if matches!(opts.scan, Scan::RowMsb | Scan::RowLsb) {
+            w = w.div_ceil(8) * 8;
+        }
+        return quote! { (#w, #h, [#(#planes),*]) }.into();
     }
-}
 
-impl image::imageops::colorops::ColorMap for BWYR {
-    type Color = Rgb<u8>;
+    let ret = if opts.interleave {
+        // B0, R0, B1, R1, ...
+        let black = pack_channel(&im, &map, 0, keyed.as_deref(), &opts);
+        let red = pack_channel(&im, &map, 2, keyed.as_deref(), &opts);
+        black
+            .into_iter()
+            .zip(red)
+            .flat_map(|(b, r)| [b, r])
+            .collect()
+    } else {
+        pack_channel(&im, &map, opts.channel, keyed.as_deref(), &opts)
+    };
 
-    fn index_of(&self, color: &Self::Color) -> usize {
-        let palette = vec![0x000000, 0xFFFFFF, 0xFFFF00, 0xFF0000];
-        let mut min = 0;
-        let mut min_dist = 0x7FFF_FFFF;
-        for (i, p) in palette.iter().enumerate() {
-            let dist = (color.0[0] as i32 - (p >> 16) as i32).abs()
-                + (color.0[1] as i32 - ((p >> 8) & 0xFF) as i32).abs()
-                + (color.0[2] as i32 - (p & 0xFF) as i32).abs();
-            if dist < min_dist {
-                min_dist = dist;
-                min = i;
-            }
-        }
-        min
+    // each row is packed to ceil(w / 8) bytes by `Scan::pack`, the tail bits are zero
+    if matches!(opts.scan, Scan::RowMsb | Scan::RowLsb) {
+        w = w.div_ceil(8) * 8;
     }
-    fn map_color(&self, color: &mut Self::Color) {
-        let idx = self.index_of(color);
-        let palette = [
-            Rgb([0x00, 0x00, 0x00]),
-            Rgb([0xFF, 0xFF, 0xFF]),
-            Rgb([0xFF, 0x00, 0x00]),
-            Rgb([0xFF, 0xFF, 0x00]),
-        ];
-        *color = palette[idx];
+
+    if opts.interleave || !opts.scan.is_image_raw() {
+        // interleaved bytes or other scan orders are not a plain 1-bit image
+        return expand_raw(w, h, None, ret, &opts.output);
     }
+    expand_raw(w, h, Some(1), ret, &opts.output)
+}
+
+/// Pack the pixels mapped to palette index `channel` into a 1-bit plane in `scan` order,
+/// leaving out pixels flagged in `keyed`; with `invert` every bit but those of the channel is set
+fn pack_channel(
+    im: &image::RgbImage,
+    map: &PaletteMap,
+    channel: u8,
+    keyed: Option<&[bool]>,
+    opts: &ImageOptions,
+) -> Vec<u8> {
+    pack_monochrome(im, map, channel, keyed, opts.invert, opts.scan)
 }
 
 /// Image reading macro for BWYR palette
 ///
+/// Each byte holds four pixels, the leftmost in the high bits. Rows are padded with zero
+/// pixels to whole bytes, so the returned width is a multiple of 4.
+///
+/// With `index_buffer`, one palette index byte per pixel is emitted instead of 2-bit packed pixels.
+///
 /// ```
-/// let (w, h, raw) = text_image::quadcolor_image!("./star-six2.png", channel = 1);
+/// let (w, h, raw) = text_image::quadcolor_image!("tests/fixtures/bwr16x1.png");
+/// assert_eq!((w, h, raw.len()), (16, 1, 4));
 /// ```
 #[proc_macro]
 pub fn quadcolor_image(input: TokenStream) -> TokenStream {
     let opts = parse_macro_input!(input as ImageOptions);
-    println!("text_image: {:#?}", opts);
+    debug_log!("text_image: {:#?}", opts);
+    if let Err(err) = opts.reject_scan("quadcolor_image") {
+        return err.to_compile_error().into();
+    }
 
-    let im = image::open(&opts.image).expect("Can not read image file");
+    let im = match open_image(&opts) {
+        Ok(im) => im,
+        Err(err) => return err.to_compile_error().into(),
+    };
     let (w, h) = im.dimensions();
 
     let mut im = im.to_rgb8();
 
-    // Floyd-Steinberg dithering
-    image::imageops::colorops::dither(&mut im, &BWYR);
+    // the same map for dithering and packing, so both agree on every pixel
+    let bwyr = PaletteMap {
+        palette: BWYR_PALETTE.to_vec(),
+        metric: opts.metric,
+    };
+
+    if let Err(err) = dither_image(&mut im, &bwyr, &opts) {
+        return err.to_compile_error().into();
+    }
+
+    if let Some(path) = &opts.save_indexed_png {
+        save_indexed_png(path, &im, &bwyr.palette, |px| bwyr.map_palette(px));
+    }
+
+    if opts.index_buffer {
+        // one palette index per pixel, no bit packing
+        let ret: Vec<u8> = im.pixels().map(|px| bwyr.map_palette(px)).collect();
+        return expand_raw(w, h, None, ret, &opts.output);
+    }
 
     let mut ret = vec![];
 
-    for pixels in im.pixels().array_chunks::<4>() {
+    for (_y, row) in im.enumerate_rows() {
         let mut n = 0u8;
-        for pix in pixels {
-            let ix = BWYR.map_palette(pix);
+        for (x, (_, _, pix)) in row.enumerate() {
+            let ix = bwyr.map_palette(pix);
             if ix != 0 && ix != 1 && ix != 2 {
-                println!("ix => {}", ix);
+                debug_log!("ix => {}", ix);
             }
             n = (n << 2) | (ix & 0b11);
+            if x % 4 == 3 {
+                ret.push(n);
+                n = 0;
+            }
+        }
+        if w % 4 != 0 {
+            // pad the last byte of the row with zero pixels
+            ret.push(n << (2 * (4 - w % 4)));
         }
-        ret.push(n);
     }
 
-    let raw_bytes = Lit::ByteStr(LitByteStr::new(&ret, proc_macro2::Span::call_site()));
+    expand_raw(w.div_ceil(4) * 4, h, None, ret, &opts.output)
+}
+
+/// Palettes of common e-paper panels by name, in controller index order
+const PALETTE_PRESETS: [(&str, &[u32]); 4] = [
+    // plain black and white panels
+    ("bw", &[0x000000, 0xFFFFFF]),
+    // three-color panels with a red plane, e.g. on SSD1680 or UC8151 controllers
+    ("bwr", &BWR_PALETTE),
+    // four-color black, white, yellow, red panels, same order as `quadcolor_image!`
+    ("bwyr", &BWYR_PALETTE),
+    // 7-color ACeP and Spectra panels, see `ACEP_PALETTE`
+    ("acep7", &ACEP_PALETTE),
+];
+
+/// Look up a `palette = "name"` preset
+fn palette_preset(name: &LitStr) -> Result<Vec<u32>> {
+    PALETTE_PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == name.value())
+        .map(|(_, palette)| palette.to_vec())
+        .ok_or_else(|| {
+            let names: Vec<String> = PALETTE_PRESETS
+                .iter()
+                .map(|(preset, _)| format!("`{}`", preset))
+                .collect();
+            syn::Error::new_spanned(
+                name,
+                format!("unknown palette, expected one of {}", names.join(", ")),
+            )
+        })
+}
 
-    let expanded = quote! {
-        (#w, #h, #raw_bytes)
+/// Image reading macro for 7-color ACeP panels, 4 bits per pixel.
+///
+/// Each byte holds two pixels, the left one in the high nibble. Odd-width rows are padded with
+/// a zero nibble, so every row starts on a byte boundary and the returned width is even.
+///
+/// The palette defaults to black, white, green, blue, red, yellow, orange (indices 0-6), and can
+/// be overridden with `palette = [0x000000, 0xFFFFFF, ...]` for panels with measured colors.
+///
+/// ```
+/// let (w, h, raw) = text_image::acep_image!("tests/fixtures/bwr16x1.png");
+/// assert_eq!((w, h, raw.len()), (16, 1, 8));
+/// ```
+#[proc_macro]
+pub fn acep_image(input: TokenStream) -> TokenStream {
+    let opts = parse_macro_input!(input as ImageOptions);
+    debug_log!("text_image: {:#?}", opts);
+    if let Err(err) = opts.reject_scan("acep_image") {
+        return err.to_compile_error().into();
+    }
+
+    let im = match open_image(&opts) {
+        Ok(im) => im,
+        Err(err) => return err.to_compile_error().into(),
     };
+    let (w, h) = im.dimensions();
 
-    TokenStream::from(expanded)
+    let mut im = im.to_rgb8();
+
+    let palette = if opts.palette.is_empty() {
+        ACEP_PALETTE.to_vec()
+    } else {
+        opts.palette.clone()
+    };
+    if palette.len() > 16 {
+        return syn::Error::new(
+            opts.image_span,
+            "palette must not have more than 16 colors for 4-bit output",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let acep = PaletteMap {
+        palette,
+        metric: opts.metric,
+    };
+
+    if let Err(err) = dither_image(&mut im, &acep, &opts) {
+        return err.to_compile_error().into();
+    }
+
+    if let Some(path) = &opts.save_indexed_png {
+        save_indexed_png(path, &im, &acep.palette, |px| acep.map_palette(px));
+    }
+
+    if opts.index_buffer {
+        // one palette index per pixel, no bit packing
+        let ret: Vec<u8> = im.pixels().map(|px| acep.map_palette(px)).collect();
+        return expand_raw(w, h, None, ret, &opts.output);
+    }
+
+    let mut ret = vec![];
+
+    for (_y, row) in im.enumerate_rows() {
+        let mut n = 0u8;
+        for (x, (_, _, px)) in row.enumerate() {
+            let ix = acep.map_palette(px);
+            if x % 2 == 0 {
+                n = ix << 4;
+            } else {
+                ret.push(n | ix);
+            }
+        }
+        if w % 2 != 0 {
+            ret.push(n);
+        }
+    }
+
+    expand_raw(w.next_multiple_of(2), h, None, ret, &opts.output)
 }
 
 /// Load a image and compress it to grayscale image of specified depth.
 ///
+/// The depth may also be read from the build environment with
+/// `gray_depth = env!("DISPLAY_DEPTH")`, see `text_image!`.
+///
 /// ```
-/// let (w, h, img_raw) = text_image::gray_image!("pattern128x128.png", Gray4);
+/// use embedded_graphics::{image::{Image, ImageRaw}, mock_display::MockDisplay, pixelcolor::Gray4, prelude::*};
+///
+/// let (w, h, img_raw) = text_image::gray_image!("core/tests/fixtures/gray5x3.png", Gray4);
 /// // or with gamma correction
-/// // let (w, h, img_raw) = text_image::gray_image!("pattern128x128.png", Gray4, gamma = 0.5);
-/// let image: ImageRaw<Gray4, LittleEndian> = ImageRaw::new(img_raw, w);
-/// image.draw(&mut fb).unwrap();
+/// // let (w, h, img_raw) = text_image::gray_image!("core/tests/fixtures/gray5x3.png", Gray4, gamma = 0.5);
+/// let image = ImageRaw::<Gray4>::new(img_raw, w);
+/// let mut display = MockDisplay::new();
+/// Image::new(&image, Point::zero()).draw(&mut display).unwrap();
+/// assert_eq!(display.affected_area().size, Size::new(w, h));
+/// ```
+///
+/// Each row starts on a byte boundary, a row ending inside a byte is padded with black, so
+/// an image 5 pixels wide has 3 bytes per Gray4 row:
+///
 /// ```
+/// let (w, h, stride, raw) =
+///     text_image::gray_image!("core/tests/fixtures/gray5x3.png", Gray4, with_stride);
+/// assert_eq!((w, h, stride, raw.len()), (5, 3, 3, 9));
+/// assert_eq!(raw[6..], [0x04, 0x8C, 0xF0]);
+/// ```
+///
+/// Gray2 and Gray4 keep the high bits of each pixel, `rounding` rounds to the nearest level
+/// instead, as for `text_image!`.
 #[proc_macro]
 pub fn gray_image(input: TokenStream) -> TokenStream {
     let opts = parse_macro_input!(input as ImageOptions);
-    println!("text_image: {:#?}", opts);
+    debug_log!("text_image: {:#?}", opts);
+    if let Err(err) = opts.reject_scan("gray_image") {
+        return err.to_compile_error().into();
+    }
 
-    let im = image::open(&opts.image).expect("Can not read image file");
+    let im = match open_image(&opts) {
+        Ok(im) => im,
+        Err(err) => return err.to_compile_error().into(),
+    };
     let (w, h) = im.dimensions();
 
     let im = im.to_luma8();
 
-    let mut ret = vec![];
+    let values: Vec<u8> = im
+        .pixels()
+        .map(|pixel| {
+            let val = pixel.0[0];
+
+            // gamma correction
+            let val = ((val as f32 / 255.0).powf(opts.gamma) * 255.0) as u8;
+
+            // the packing keeps the high bits, put the nearest level there
+            if opts.rounding && matches!(opts.gray_depth, 2 | 4) {
+                let levels = (1u16 << opts.gray_depth) - 1;
+                let level = (val as u16 * levels + 127) / 255;
+                (level << (8 - opts.gray_depth)) as u8
+            } else {
+                val
+            }
+        })
+        .collect();
+    // each row starts on a byte boundary, a partial last byte is padded with black
+    let ret = pack_gray(&values, w, opts.gray_depth as u8, 0);
+
+    expand_raw(w, h, Some(opts.gray_depth), ret, &opts.output)
+}
+
+/// Kind of a generated test pattern
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pattern {
+    /// squares of `stripe` pixels, alternating on and off, on at the top-left
+    Checkerboard,
+    /// vertical stripes of `stripe` pixels, on at the left
+    VStripes,
+    /// horizontal stripes of `stripe` pixels, on at the top
+    HStripes,
+    /// vertical bars of every gray level of the depth from black to white, at most 16
+    Bars,
+}
+
+impl Parse for Pattern {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let (value, span) = parse_keyword(input)?;
+
+        match &*value {
+            "checkerboard" => Ok(Pattern::Checkerboard),
+            "vstripes" => Ok(Pattern::VStripes),
+            "hstripes" => Ok(Pattern::HStripes),
+            "bars" => Ok(Pattern::Bars),
+            _ => Err(syn::Error::new(
+                span,
+                "expected `checkerboard`, `vstripes`, `hstripes` or `bars`",
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TestPatternOptions {
+    pattern: Pattern,
+    width: u32,
+    height: u32,
+    /// size of a stripe or checkerboard square
+    stripe: u32,
+    /// gray depth, 1, 2, 4, 8
+    gray_depth: i32,
+    /// pixel scan order of 1-bit output
+    scan: Scan,
+    output: OutputOptions,
+}
+
+impl Parse for TestPatternOptions {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut opts = TestPatternOptions {
+            pattern: Pattern::Checkerboard,
+            width: 0,
+            height: 0,
+            stripe: 1,
+            gray_depth: 1,
+            scan: Scan::RowMsb,
+            output: OutputOptions::default(),
+        };
+        let mut scan_span = None;
+
+        while !input.is_empty() {
+            let name: Ident = input.parse()?;
+            check_option(&name, &[TEST_PATTERN_OPTIONS, OUTPUT_OPTIONS, GRAY_OPTIONS])?;
 
-    let steps_per_pixel = 8 / opts.gray_depth;
-    let shift_per_pixel = match opts.gray_depth {
-        8 => 0,
-        4 => 4,
-        2 => 6,
-        1 => 7,
-        _ => unreachable!(),
+            match &*name.to_string() {
+                "pattern" => {
+                    input.parse::<Token![=]>()?;
+                    opts.pattern = input.parse()?;
+                }
+                "width" | "height" | "stripe" => {
+                    input.parse::<Token![=]>()?;
+                    let value: LitInt = input.parse()?;
+                    let n: u32 = value.base10_parse()?;
+                    if n == 0 {
+                        return Err(syn::Error::new_spanned(value, "expected a positive size"));
+                    }
+                    match &*name.to_string() {
+                        "width" => opts.width = n,
+                        "height" => opts.height = n,
+                        _ => opts.stripe = n,
+                    }
+                }
+                "scan" => {
+                    input.parse::<Token![=]>()?;
+                    opts.scan = parse_keyword_as(input)?;
+                    scan_span = Some(name.span());
+                }
+                option if OUTPUT_OPTIONS.contains(&option) => {
+                    opts.output.parse_option(&name, input)?;
+                }
+                "gray_depth" => {
+                    input.parse::<Token![=]>()?;
+                    opts.gray_depth = parse_gray_depth(input)? as i32;
+                }
+                "Gray2" => {
+                    opts.gray_depth = 2;
+                }
+                "Gray4" => {
+                    opts.gray_depth = 4;
+                }
+                "Gray8" => {
+                    opts.gray_depth = 8;
+                }
+                // names not in the option lists were rejected by `check_option`
+                _ => unreachable!(),
+            }
+
+            let _ = input.parse::<Token![,]>();
+        }
+
+        if opts.width == 0 || opts.height == 0 {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "required options `width` and `height` are missing",
+            ));
+        }
+
+        check_scan(opts.scan, scan_span, opts.gray_depth, false)?;
+
+        Ok(opts)
+    }
+}
+
+/// Generate a test pattern for display bring-up, without an image file.
+///
+/// Parameters:
+/// - `width`, `height`: size of the image, required
+/// - `pattern`: `checkerboard` (default), `vstripes`, `hstripes`, or `bars` with every gray
+///   level of the depth from black to white (at most 16 levels)
+/// - `stripe`: size of a stripe or checkerboard square in pixels, default 1
+/// - `Gray2`, `Gray4`, `Gray8`: gray depth, default 1-bit, or `gray_depth` as for `text_image!`
+/// - `scan`: pixel scan order of 1-bit output, see `text_image!`
+/// - `pad_to`, `const_fn`, `as_image_raw`, `with_stride`, `out_dir`, `as_array`, `compress`: as
+///   for `text_image!`
+///
+/// The top-left square or stripe is on (white). Rows are padded to whole bytes, the data is
+/// packed the same way `text_image!` and `gray_image!` pack theirs.
+///
+/// ```
+/// let (w, h, raw) = text_image::test_pattern!(width = 8, height = 8, pattern = checkerboard);
+/// assert_eq!(raw[..4], [0xAA, 0x55, 0xAA, 0x55]);
+/// // 4 gray bars of 4 pixels each
+/// let (w, h, raw) = text_image::test_pattern!(width = 16, height = 4, pattern = bars, Gray2);
+/// ```
+#[proc_macro]
+pub fn test_pattern(input: TokenStream) -> TokenStream {
+    let opts = parse_macro_input!(input as TestPatternOptions);
+    debug_log!("text_image: {:#?}", opts);
+
+    let TestPatternOptions {
+        pattern,
+        width: w,
+        height: h,
+        stripe,
+        gray_depth,
+        scan,
+        ..
+    } = opts;
+
+    let levels = (1u32 << gray_depth).min(16).min(w);
+    let value = |x: u32, y: u32| -> u8 {
+        let on = match pattern {
+            Pattern::Checkerboard => (x / stripe + y / stripe).is_multiple_of(2),
+            Pattern::VStripes => (x / stripe).is_multiple_of(2),
+            Pattern::HStripes => (y / stripe).is_multiple_of(2),
+            Pattern::Bars => {
+                let level = x * levels / w;
+                return (level * 255 / (levels - 1).max(1)) as u8;
+            }
+        };
+        if on {
+            0xFF
+        } else {
+            0x00
+        }
     };
 
-    let mut c = 0;
-    let mut n = 0u8;
-    for pixel in im.pixels() {
-        let val = pixel.0[0];
+    expand_generated(w, h, gray_depth, scan, value, &opts.output)
+}
 
-        // gamma correction
-        let val = ((val as f32 / 255.0).powf(opts.gamma) * 255.0) as u8;
+/// Pack a generated `w` x `h` image given by the 8-bit luma `value(x, y)` and expand it.
+///
+/// Rows are padded to whole bytes, 1-bit output is packed in `scan` order.
+fn expand_generated(
+    w: u32,
+    h: u32,
+    gray_depth: i32,
+    scan: Scan,
+    value: impl Fn(u32, u32) -> u8,
+    output: &OutputOptions,
+) -> TokenStream {
+    if gray_depth == 1 {
+        let ret = scan.pack(w, h, |x, y| value(x, y) & 0x80 != 0);
+        let (w, depth) = match scan {
+            Scan::RowMsb => (w.div_ceil(8) * 8, Some(1)),
+            Scan::RowLsb => (w.div_ceil(8) * 8, None),
+            _ => (w, None),
+        };
+        return expand_raw(w, h, depth, ret, output);
+    }
 
-        n = (n << opts.gray_depth) | (val >> shift_per_pixel);
-        c += 1;
+    let per_byte = 8 / gray_depth as u32;
+    let padded_w = w.div_ceil(per_byte) * per_byte;
+    let values: Vec<u8> = (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .map(|(x, y)| value(x, y))
+        .collect();
+    let ret = pack_gray(&values, w, gray_depth as u8, 0);
 
-        if c == steps_per_pixel {
-            ret.push(n);
-            n = 0;
-            c = 0;
+    expand_raw(padded_w, h, Some(gray_depth), ret, output)
+}
+
+#[derive(Debug)]
+struct SevenSegmentOptions {
+    text: LitStr,
+    /// height of a digit
+    height: u32,
+    /// thickness of a segment
+    thickness: u32,
+    /// horizontal shift of the top row against the bottom row, for italic digits
+    slant: u32,
+    /// gray depth, 1, 2, 4, 8
+    gray_depth: i32,
+    /// pixel scan order of 1-bit output
+    scan: Scan,
+    output: OutputOptions,
+}
+
+impl Parse for SevenSegmentOptions {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut opts = SevenSegmentOptions {
+            text: input.parse()?,
+            height: 16,
+            thickness: 2,
+            slant: 0,
+            gray_depth: 1,
+            scan: Scan::RowMsb,
+            output: OutputOptions::default(),
+        };
+        let mut scan_span = None;
+
+        if let Some(c) = opts
+            .text
+            .value()
+            .chars()
+            .find(|&c| seven_segment_glyph(c).is_none())
+        {
+            return Err(syn::Error::new_spanned(
+                &opts.text,
+                format!(
+                    "unsupported character {:?}, expected `0-9`, `.`, `:`, `-` or space",
+                    c
+                ),
+            ));
+        }
+
+        while input.parse::<Token![,]>().is_ok() {
+            if input.is_empty() {
+                break;
+            }
+
+            let name: Ident = input.parse()?;
+            check_option(
+                &name,
+                &[SEVEN_SEGMENT_OPTIONS, OUTPUT_OPTIONS, GRAY_OPTIONS],
+            )?;
+
+            match &*name.to_string() {
+                "height" | "thickness" | "slant" => {
+                    input.parse::<Token![=]>()?;
+                    let value: LitInt = input.parse()?;
+                    let n = value.base10_parse()?;
+                    match &*name.to_string() {
+                        "height" => opts.height = n,
+                        "thickness" => opts.thickness = n,
+                        _ => opts.slant = n,
+                    }
+                }
+                "scan" => {
+                    input.parse::<Token![=]>()?;
+                    opts.scan = parse_keyword_as(input)?;
+                    scan_span = Some(name.span());
+                }
+                option if OUTPUT_OPTIONS.contains(&option) => {
+                    opts.output.parse_option(&name, input)?;
+                }
+                "gray_depth" => {
+                    input.parse::<Token![=]>()?;
+                    opts.gray_depth = parse_gray_depth(input)? as i32;
+                }
+                "Gray2" => {
+                    opts.gray_depth = 2;
+                }
+                "Gray4" => {
+                    opts.gray_depth = 4;
+                }
+                "Gray8" => {
+                    opts.gray_depth = 8;
+                }
+                // names not in the option lists were rejected by `check_option`
+                _ => unreachable!(),
+            }
+        }
+
+        if opts.thickness == 0 || opts.height < 3 * opts.thickness {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "height must be at least 3 times the segment thickness",
+            ));
+        }
+        check_scan(opts.scan, scan_span, opts.gray_depth, false)?;
+
+        Ok(opts)
+    }
+}
+
+/// Segments of a character, `abcdefg` as bits 0 to 6, or None if unsupported.
+///
+/// `.` and `:` are not segments, they are drawn as narrow dots by `seven_segment!`.
+fn seven_segment_glyph(c: char) -> Option<u8> {
+    match c {
+        '0' => Some(0x3F),
+        '1' => Some(0x06),
+        '2' => Some(0x5B),
+        '3' => Some(0x4F),
+        '4' => Some(0x66),
+        '5' => Some(0x6D),
+        '6' => Some(0x7D),
+        '7' => Some(0x07),
+        '8' => Some(0x7F),
+        '9' => Some(0x6F),
+        '-' => Some(0x40),
+        ' ' | '.' | ':' => Some(0x00),
+        _ => None,
+    }
+}
+
+/// Generate seven-segment style digits without a font, for clocks and meters.
+///
+/// The text may contain `0-9`, `.`, `:`, `-` and space, a space is as wide as a digit.
+///
+/// Parameters:
+/// - `height`: height of a digit in pixels, default 16, the digit is half as wide
+/// - `thickness`: thickness of a segment, default 2, also the gap between characters
+/// - `slant`: shift the top row this many pixels right of the bottom row, for italic digits
+/// - `Gray2`, `Gray4`, `Gray8`, `gray_depth`, `scan`, `pad_to`, `const_fn`, `as_image_raw`:
+///   as for `test_pattern!`
+///
+/// Segments are on (white), the background off.
+///
+/// ```
+/// let (w, h, raw) = text_image::seven_segment!("12:45", height = 24, thickness = 3);
+/// ```
+///
+/// ```text
+///  aaa
+/// f   b
+/// f   b
+///  ggg
+/// e   c
+/// e   c
+///  ddd
+/// ```
+#[proc_macro]
+pub fn seven_segment(input: TokenStream) -> TokenStream {
+    let opts = parse_macro_input!(input as SevenSegmentOptions);
+    debug_log!("text_image: {:#?}", opts);
+
+    let (h, t) = (opts.height, opts.thickness);
+    let digit_w = (h / 2).max(3 * t);
+    let mid = (h - t) / 2;
+
+    // lit rectangles as (x0, y0, x1, y1), exclusive, before slanting
+    let mut rects = vec![];
+    let mut x = 0;
+    for c in opts.text.value().chars() {
+        if x > 0 {
+            x += t;
+        }
+        match c {
+            '.' => {
+                rects.push((x, h - t, x + t, h));
+                x += t;
+            }
+            ':' => {
+                rects.push((x, h / 3 - t / 2, x + t, h / 3 - t / 2 + t));
+                rects.push((x, h * 2 / 3 - t / 2, x + t, h * 2 / 3 - t / 2 + t));
+                x += t;
+            }
+            _ => {
+                let segments = seven_segment_glyph(c).unwrap();
+                let r = x + digit_w;
+                let all = [
+                    (x + t, 0, r - t, t),
+                    (r - t, t, r, mid),
+                    (r - t, mid + t, r, h - t),
+                    (x + t, h - t, r - t, h),
+                    (x, mid + t, x + t, h - t),
+                    (x, t, x + t, mid),
+                    (x + t, mid, r - t, mid + t),
+                ];
+                for (i, rect) in all.into_iter().enumerate() {
+                    if segments & (1 << i) != 0 {
+                        rects.push(rect);
+                    }
+                }
+                x += digit_w;
+            }
         }
     }
+    let slant = opts.slant;
+    let w = (x + slant).max(1);
+
+    let value = |x: u32, y: u32| -> u8 {
+        // shear, the top row is shifted by `slant`
+        let shift = slant * (h - 1 - y) / (h - 1);
+        let Some(x) = x.checked_sub(shift) else {
+            return 0x00;
+        };
+        let on = rects
+            .iter()
+            .any(|&(x0, y0, x1, y1)| (x0..x1).contains(&x) && (y0..y1).contains(&y));
+        if on {
+            0xFF
+        } else {
+            0x00
+        }
+    };
 
-    let raw_bytes = Lit::ByteStr(LitByteStr::new(&ret, proc_macro2::Span::call_site()));
+    expand_generated(w, h, opts.gray_depth, opts.scan, value, &opts.output)
+}
+
+/// Generators usable in `asset_bundle!` entries
+const BUNDLE_GENERATORS: [&str; 7] = [
+    "text_image",
+    "monochrome_image",
+    "quadcolor_image",
+    "acep_image",
+    "gray_image",
+    "test_pattern",
+    "seven_segment",
+];
+
+/// Options of the generators emitting something other than a `(w, h, &[u8])` tuple
+const BUNDLE_REJECTED_OPTIONS: [&str; 7] = [
+    "with_stride",
+    "content_width",
+    "as_array",
+    "as_image_raw",
+    "const_fn",
+    "planes",
+    "all_planes",
+];
+
+struct AssetBundle {
+    vis: syn::Visibility,
+    name: Ident,
+    entries: Vec<(Ident, syn::Macro)>,
+}
+
+impl Parse for AssetBundle {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let vis = input.parse()?;
+        input.parse::<Token![mod]>()?;
+        let name = input.parse()?;
+
+        let content;
+        syn::braced!(content in input);
+        let mut entries: Vec<(Ident, syn::Macro)> = vec![];
+        while !content.is_empty() {
+            let entry: Ident = content.parse()?;
+            content.parse::<Token![=]>()?;
+            let mac: syn::Macro = content.parse()?;
+
+            let generator = mac.path.segments.last().map(|seg| seg.ident.to_string());
+            if !generator.is_some_and(|generator| BUNDLE_GENERATORS.contains(&&*generator)) {
+                return Err(syn::Error::new_spanned(
+                    &mac.path,
+                    format!("expected one of {}", BUNDLE_GENERATORS.join(", ")),
+                ));
+            }
+            // options are `name` or `name = value`, each starting the macro input or after a comma
+            let mut option_start = true;
+            for token in mac.tokens.clone() {
+                match &token {
+                    proc_macro2::TokenTree::Ident(ident)
+                        if option_start
+                            && BUNDLE_REJECTED_OPTIONS.contains(&&*ident.to_string()) =>
+                    {
+                        return Err(syn::Error::new_spanned(
+                            ident,
+                            format!(
+                                "`{}` is not supported in `asset_bundle!`, assets are `(u32, u32, &[u8])`",
+                                ident
+                            ),
+                        ));
+                    }
+                    proc_macro2::TokenTree::Punct(punct) if punct.as_char() == ',' => {
+                        option_start = true
+                    }
+                    _ => option_start = false,
+                }
+            }
+            if entries.iter().any(|(name, _)| *name == entry) {
+                return Err(syn::Error::new_spanned(entry, "duplicate asset name"));
+            }
+            entries.push((entry, mac));
+
+            if content.is_empty() {
+                break;
+            }
+            content.parse::<Token![,]>()?;
+        }
+
+        Ok(AssetBundle { vis, name, entries })
+    }
+}
+
+/// Bundle many assets into a module, with a lookup by name.
+///
+/// Each entry is `name = generator!(...)`, where the generator is any macro of this crate
+/// emitting a `(w, h, raw)` tuple. Each asset becomes a `pub const` named in upper case,
+/// and `get(name)` returns it by name at runtime. `NAMES` lists the asset names in order.
+///
+/// Generators are called by the path as written, resolved from the module enclosing the
+/// bundle, so both `text_image::test_pattern!` and an imported `test_pattern!` work.
+///
+/// ```
+/// text_image::asset_bundle! {
+///     pub mod assets {
+///         title = text_image::text_image!(
+///             text = "Settings",
+///             font = "core/tests/fixtures/DejaVuSans.ttf",
+///             font_size = 16.0,
+///             Gray4
+///         ),
+///         grid = text_image::test_pattern!(width = 8, height = 6, pattern = checkerboard),
+///         clock = text_image::seven_segment!("12:45", height = 24, thickness = 3),
+///     }
+/// }
+///
+/// // `assets` holds `TITLE`, `GRID` and `CLOCK`
+/// assert_eq!(assets::NAMES, ["title", "grid", "clock"]);
+/// assert_eq!(assets::GRID.0, 8);
+///
+/// let (w, h, raw) = assets::get("title").unwrap();
+/// assert_eq!((w, h, raw.len()), (56, 16, 56 * 16 / 2));
+/// let (w, h, raw) = assets::get("grid").unwrap();
+/// assert_eq!((w, h, raw.len()), (8, 6, 6));
+/// let (w, h, _) = assets::get("clock").unwrap();
+/// assert_eq!((w, h), (64, 24));
+/// assert!(assets::get("logo").is_none());
+/// ```
+///
+/// Assets may have different depths, so the lookup returns the raw tuple rather than an
+/// `ImageRaw`, the caller knows the color type of each asset. For the same reason options
+/// changing the shape of the output, `with_stride`, `content_width`, `as_array`,
+/// `as_image_raw`, `const_fn`, `planes` and `all_planes`, are rejected:
+///
+/// ```compile_fail
+/// text_image::asset_bundle! {
+///     mod assets {
+///         title = text_image::text_image!(text = "Settings", font = @dejavu, content_width),
+///     }
+/// }
+/// ```
+#[proc_macro]
+pub fn asset_bundle(input: TokenStream) -> TokenStream {
+    let AssetBundle { vis, name, entries } = parse_macro_input!(input as AssetBundle);
+
+    let consts = entries.iter().map(|(entry, mac)| {
+        let ident = Ident::new(&entry.to_string().to_uppercase(), entry.span());
+        // the module is one level down, relative paths start one level further up
+        let mut path = mac.path.clone();
+        let first = &path.segments[0].ident;
+        let up = Ident::new("super", first.span());
+        if first == "self" {
+            path.segments[0].ident = up;
+        } else if first == "super" {
+            path.segments.insert(0, syn::PathSegment::from(up));
+        }
+        let tokens = &mac.tokens;
+        quote! {
+            pub const #ident: (u32, u32, &[u8]) = #path!(#tokens);
+        }
+    });
+    let names: Vec<String> = entries.iter().map(|(entry, _)| entry.to_string()).collect();
+    let idents = entries
+        .iter()
+        .map(|(entry, _)| Ident::new(&entry.to_string().to_uppercase(), entry.span()));
 
     let expanded = quote! {
-        (#w, #h, #raw_bytes)
+        #vis mod #name {
+            // generators imported into the enclosing module
+            #[allow(unused_imports)]
+            use super::*;
+
+            #(#consts)*
+
+            /// Names of the assets in this bundle
+            pub const NAMES: &[&str] = &[#(#names),*];
+
+            /// Look up an asset by name, as `(width, height, raw)`
+            pub fn get(name: &str) -> Option<(u32, u32, &'static [u8])> {
+                match name {
+                    #(#names => Some(#idents),)*
+                    _ => None,
+                }
+            }
+        }
     };
 
     TokenStream::from(expanded)
@@ -0,0 +1,169 @@
+//! Byte-level checks of the image macros, on generated patterns and the small images in
+//! `tests/fixtures`
+
+use text_image::{
+    gray_image, monochrome_image, quadcolor_image, seven_segment, test_pattern, text_image,
+};
+
+#[test]
+fn interleave_black_and_red() {
+    // black at x = 0, 8 and 9, red at x = 1 and 15
+    let (w, h, black) = monochrome_image!("tests/fixtures/bwr16x1.png", channel = 0);
+    let (_, _, red) = monochrome_image!("tests/fixtures/bwr16x1.png", channel = 2);
+    assert_eq!((w, h), (16, 1));
+    assert_eq!(black, &[0x80, 0xC0]);
+    assert_eq!(red, &[0x40, 0x01]);
+    let (_, _, raw) = monochrome_image!("tests/fixtures/bwr16x1.png", interleave);
+    assert_eq!(raw, &[0x80, 0x40, 0xC0, 0x01]);
+}
+
+#[test]
+fn color_key_is_never_inked() {
+    // black at x = 0 and 2, magenta at x = 1
+    let (_, _, black) = monochrome_image!("tests/fixtures/keyed8x1.png", channel = 0);
+    assert_eq!(black, &[0xA0]);
+    let (_, _, black) = monochrome_image!(
+        "tests/fixtures/keyed8x1.png",
+        channel = 0,
+        color_key = 0xFF00FF
+    );
+    assert_eq!(black, &[0xA0]);
+    // keying the foreground color empties its plane
+    let (_, _, black) = monochrome_image!(
+        "tests/fixtures/keyed8x1.png",
+        channel = 0,
+        color_key = 0x000000
+    );
+    assert_eq!(black, &[0x00]);
+    let (_, _, black) = monochrome_image!(
+        "tests/fixtures/keyed8x1.png",
+        channel = 0,
+        color_key = 0x101010,
+        key_tolerance = 16
+    );
+    assert_eq!(black, &[0x00]);
+    // with invert, a set bit is no ink
+    let (_, _, black) = monochrome_image!("tests/fixtures/keyed8x1.png", channel = 0, invert);
+    assert_eq!(black, &[0x5F]);
+    let (_, _, black) = monochrome_image!(
+        "tests/fixtures/keyed8x1.png",
+        channel = 0,
+        color_key = 0x000000,
+        invert
+    );
+    assert_eq!(black, &[0xFF]);
+}
+
+#[test]
+fn checkerboard_alternates() {
+    let (w, h, raw) = test_pattern!(width = 8, height = 2, pattern = checkerboard, stripe = 1);
+    assert_eq!((w, h, raw), (8, 2, &[0xAA, 0x55]));
+    let (_, _, raw) = test_pattern!(width = 8, height = 4, pattern = checkerboard, stripe = 2);
+    assert_eq!(raw, &[0xCC, 0xCC, 0x33, 0x33]);
+    let (_, _, raw) = test_pattern!(width = 4, height = 2, pattern = checkerboard, Gray2);
+    assert_eq!(raw, &[0xCC, 0x33]);
+}
+
+#[test]
+fn seven_segment_eight_and_space() {
+    // a and d, then f and b, g, e and c, then d, 2 pixels thick
+    let bar: &[u8] = &[0x3C; 2];
+    let sides: &[u8] = &[0xC3; 5];
+    let eight = [bar, sides, bar, sides, bar].concat();
+    let (w, h, raw) = seven_segment!("8", height = 16, thickness = 2);
+    assert_eq!((w, h), (8, 16));
+    assert_eq!(raw, &eight[..]);
+    let (w, h, raw) = seven_segment!(" ", height = 16, thickness = 2);
+    assert_eq!((w, h), (8, 16));
+    assert_eq!(raw, &[0x00; 16]);
+}
+
+#[test]
+fn gray_rounding() {
+    // each row is 0x00, 0x48, 0x88, 0xC8, 0xF8
+    let (_, _, raw) = gray_image!("core/tests/fixtures/gray5x3.png", Gray4, rounding);
+    assert_eq!(raw[..3], [0x04, 0x8C, 0xF0]);
+    // 0xC8 is nearer to level 2 of Gray2 than to 3, which its high bits are
+    let (_, _, raw) = gray_image!("core/tests/fixtures/gray5x3.png", Gray2);
+    assert_eq!(raw[..2], [0x1B, 0xC0]);
+    let (_, _, raw) = gray_image!("core/tests/fixtures/gray5x3.png", Gray2, rounding);
+    assert_eq!(raw[..2], [0x1A, 0xC0]);
+}
+
+#[test]
+fn quadcolor_pads_rows() {
+    // each row is black, white, yellow, red, black, white, yellow
+    let (w, h, raw) = quadcolor_image!("tests/fixtures/bwyr7x3.png");
+    assert_eq!((w, h), (8, 3));
+    // indices 0, 1, 3, 2, 0, 1, 3, the last pixel of a row is kept and the padding after it is 0
+    assert_eq!(raw, &[0x1E, 0x1C, 0x1E, 0x1C, 0x1E, 0x1C]);
+}
+
+#[test]
+fn invert_twice() {
+    let (_, _, red) = monochrome_image!("tests/fixtures/bwr16x1.png", channel = 2);
+    let (_, _, inverted) = monochrome_image!("tests/fixtures/bwr16x1.png", channel = 2, invert);
+    assert_eq!(inverted, &[0xBF, 0xFE]);
+    let twice: Vec<u8> = inverted.iter().map(|b| !b).collect();
+    assert_eq!(twice, red);
+    let (_, _, black) = monochrome_image!("tests/fixtures/bwr16x1.png", channel = 0);
+    let (_, _, inverted) = monochrome_image!("tests/fixtures/bwr16x1.png", channel = 0, invert);
+    let twice: Vec<u8> = inverted.iter().map(|b| !b).collect();
+    assert_eq!(twice, black);
+}
+
+#[test]
+fn black_and_red_planes() {
+    let (w, h, black, red) = monochrome_image!("tests/fixtures/bwr16x1.png", planes);
+    assert_eq!((w, h), (16, 1));
+    assert_eq!((black, red), (&[0x80, 0xC0], &[0x40, 0x01]));
+    let (_, _, [black, white, red]) = monochrome_image!("tests/fixtures/bwr16x1.png", all_planes);
+    assert_eq!(
+        (black, white, red),
+        (&[0x80, 0xC0], &[0x3F, 0x3E], &[0x40, 0x01])
+    );
+}
+
+#[test]
+fn rotate_and_flip_corner() {
+    // 8x4, black only at the top-left
+    let (w, h, raw) = monochrome_image!("tests/fixtures/corner8x4.png");
+    assert_eq!((w, h, raw), (8, 4, &[0x80, 0, 0, 0]));
+    // clockwise, to the top-right of a 4x8 image, its rows padded to 8 pixels
+    let (w, h, raw) = monochrome_image!("tests/fixtures/corner8x4.png", rotate = 90);
+    assert_eq!((w, h), (8, 8));
+    assert_eq!(raw, &[0x10, 0, 0, 0, 0, 0, 0, 0]);
+    let (_, _, raw) = monochrome_image!("tests/fixtures/corner8x4.png", rotate = 180);
+    assert_eq!(raw, &[0, 0, 0, 0x01]);
+    let (_, _, raw) = monochrome_image!("tests/fixtures/corner8x4.png", rotate = 270);
+    assert_eq!(raw, &[0, 0, 0, 0, 0, 0, 0, 0x80]);
+    let (_, _, raw) = monochrome_image!("tests/fixtures/corner8x4.png", flip_h);
+    assert_eq!(raw, &[0x01, 0, 0, 0]);
+    let (_, _, raw) = monochrome_image!("tests/fixtures/corner8x4.png", flip_v);
+    assert_eq!(raw, &[0, 0, 0, 0x80]);
+}
+
+#[test]
+fn runtime_matches_macro() {
+    let (w, h, raw) = text_image!(
+        text = "Hi",
+        font = "core/tests/fixtures/DejaVuSans.ttf",
+        font_size = 16.0,
+        Gray4
+    );
+    let opts = text_image_core::TextImageOptions {
+        text: "Hi".into(),
+        font: concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/core/tests/fixtures/DejaVuSans.ttf"
+        )
+        .into(),
+        font_size: 16.0,
+        gray_depth: 4,
+        ..Default::default()
+    };
+    assert_eq!(
+        text_image_core::text_image(&opts).unwrap(),
+        (w, h, raw.to_vec())
+    );
+}